@@ -0,0 +1,31 @@
+// Runs the default dam break headlessly, logs the diagnostics of every frame
+// and the solver timings of the last frames at the end.
+//
+//   cargo run --release --example diagnostics -- sph 300
+//
+// Arguments: solver (sph, mpm or flip) and optionally the number of frames.
+
+use rust_fluid::solver::{Scene, SolverKind, State};
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let solver_kind = match args.first().map(String::as_str).unwrap_or("sph") {
+        "sph" => SolverKind::Sph,
+        "mpm" => SolverKind::Mpm,
+        "flip" => SolverKind::Flip,
+        other => {
+            eprintln!("unknown solver: {}", other);
+            std::process::exit(1);
+        }
+    };
+    let frames: u32 = args.get(1).map(|arg| arg.parse().expect("not a number")).unwrap_or(300);
+
+    let mut state = State::new(&Scene::default());
+    state.solver_kind = solver_kind;
+    for frame in 0..frames {
+        state.update(&[]);
+        println!("{:5} {}", frame, state.diagnostics());
+    }
+    println!();
+    print!("{}", state.profiler());
+}
@@ -1,5 +1,5 @@
 import { simd } from 'wasm-feature-detect';
-import { start } from './pkg/rust_fluid.js'
+import { Simulation } from './pkg/rust_fluid.js'
 
 async function run() {
     const multithread = await import('./pkg/rust_fluid.js');
@@ -15,7 +15,250 @@ async function run() {
     const threadCountElement = document.getElementById('thread-count');
     threadCountElement.textContent = `Running with ${numThreads} threads`;
 
-    start();
+    // The page controls map onto the params of the simulation
+    const canvas = document.getElementById('canvas');
+    const sliderValue = document.getElementById('slider-value');
+    const sceneParams = () => ({
+        material: document.getElementById('material').value,
+        field_height: parseFloat(document.getElementById('field-height').value),
+        fill_level: parseFloat(document.getElementById('fill-level').value),
+    });
+    const sim = new Simulation(canvas, {
+        count: parseInt(sliderValue.textContent),
+        params: {
+            ...sceneParams(),
+            solver: document.getElementById('solver').value,
+            color_mode: document.getElementById('color-mode').value,
+            color_map: document.getElementById('color-map').value,
+            render_mode: document.getElementById('render-mode').value,
+            overlay: document.getElementById('overlay').value,
+            flip_ratio: parseFloat(document.getElementById('flip-ratio').value),
+        },
+    });
+
+    document.getElementById('reset-button').addEventListener('click', () => {
+        sim.set_param('count', parseInt(sliderValue.textContent));
+        for (const [name, value] of Object.entries(sceneParams())) {
+            sim.set_param(name, value);
+        }
+        sim.reset();
+    });
+    // Materials without a model in the selected solver would run as liquid, they are disabled
+    const solverSelect = document.getElementById('solver');
+    const materialSelect = document.getElementById('material');
+    const markUnsupportedMaterials = () => {
+        for (const option of materialSelect.options) {
+            option.disabled = !Simulation.supports_material(solverSelect.value, option.value);
+        }
+    };
+    markUnsupportedMaterials();
+    solverSelect.addEventListener('change', markUnsupportedMaterials);
+    for (const [id, name] of [['solver', 'solver'], ['color-mode', 'color_mode'], ['color-map', 'color_map'], ['render-mode', 'render_mode'], ['overlay', 'overlay']]) {
+        const element = document.getElementById(id);
+        element.addEventListener('change', () => sim.set_param(name, element.value));
+    }
+    const flipRatio = document.getElementById('flip-ratio');
+    flipRatio.addEventListener('input', () => sim.set_param('flip_ratio', parseFloat(flipRatio.value)));
+
+    // Refreshed every few frames so that the numbers stay readable
+    const diagnosticsElement = document.getElementById('diagnostics');
+    const showDiagnostics = document.getElementById('show-diagnostics');
+    showDiagnostics.addEventListener('change', () => { diagnosticsElement.hidden = !showDiagnostics.checked; });
+    let frameCount = 0;
+    const updateDiagnostics = () => {
+        const d = sim.diagnostics();
+        diagnosticsElement.textContent = [
+            `time              ${d.time.toFixed(2)} s`,
+            `particles         ${d.particle_count}`,
+            `kinetic energy    ${d.kinetic_energy.toFixed(4)}`,
+            `potential energy  ${d.potential_energy.toFixed(4)}`,
+            `momentum          (${d.momentum_x.toFixed(3)}, ${d.momentum_y.toFixed(3)})`,
+            `density error     ${(100 * d.mean_density_error).toFixed(2)} % mean, ${(100 * d.max_density_error).toFixed(1)} % max`,
+            `max speed         ${d.max_speed.toFixed(3)} m/s`,
+            `neighbors         ${d.min_neighbors} / ${d.mean_neighbors.toFixed(1)} / ${d.max_neighbors} (min / mean / max)`,
+        ].join('\n');
+    };
+
+    // Solver phases from the simulation, rendering measured here. All in ms per frame.
+    const profilerElement = document.getElementById('profiler');
+    const showProfiler = document.getElementById('show-profiler');
+    showProfiler.addEventListener('change', () => { profilerElement.hidden = !showProfiler.checked; });
+    const renderTimes = [];
+    const updateProfiler = () => {
+        const row = (name, mean, p50, p95, max) =>
+            name.padEnd(18) + [mean, p50, p95, max].map((t) => t.toFixed(2).padStart(8)).join('');
+        const lines = [`${'phase'.padEnd(18)}${['mean', 'p50', 'p95', 'max'].map((s) => s.padStart(8)).join('')}`];
+        for (const p of sim.profile()) {
+            lines.push(row(p.phase, p.mean, p.p50, p.p95, p.max));
+        }
+        const sorted = [...renderTimes].sort((a, b) => a - b);
+        const at = (q) => sorted[Math.round((sorted.length - 1) * q)] ?? 0;
+        const mean = sorted.reduce((sum, t) => sum + t, 0) / Math.max(sorted.length, 1);
+        lines.push(row('render', mean, at(0.5), at(0.95), at(1)));
+        lines.push(`${sim.particle_count()} particles`);
+        profilerElement.textContent = lines.join('\n');
+    };
+
+    const frame = () => {
+        sim.step();
+        const renderStart = performance.now();
+        sim.render();
+        renderTimes.push(performance.now() - renderStart);
+        if (renderTimes.length > 120) {
+            renderTimes.shift();
+        }
+        if (frameCount++ % 10 === 0) {
+            if (showDiagnostics.checked) {
+                updateDiagnostics();
+            }
+            if (showProfiler.checked) {
+                updateProfiler();
+            }
+        }
+        requestAnimationFrame(frame);
+    };
+    requestAnimationFrame(frame);
+
+    // Tilting a phone or tablet tilts gravity, once turned on with the Tilt checkbox.
+    // Off, the orientation events leave gravity alone, so that Reset gravity sticks.
+    const tiltCheckbox = document.getElementById('tilt-gravity');
+    window.addEventListener('deviceorientation', (event) => {
+        if (!tiltCheckbox.checked || event.beta === null || event.gamma === null) {
+            return;
+        }
+        const g = 9.8;
+        const beta = event.beta * Math.PI / 180;
+        const gamma = event.gamma * Math.PI / 180;
+        // In device coordinates, then rotated with the screen into canvas coordinates
+        const x = g * Math.sin(gamma);
+        const y = -g * Math.sin(beta);
+        const angle = (screen.orientation ? screen.orientation.angle : window.orientation || 0) * Math.PI / 180;
+        sim.set_gravity(x * Math.cos(angle) - y * Math.sin(angle), x * Math.sin(angle) + y * Math.cos(angle));
+    });
+    tiltCheckbox.addEventListener('change', async () => {
+        if (!tiltCheckbox.checked) {
+            sim.reset_gravity();
+            return;
+        }
+        // iOS only delivers the events after asking, from a user gesture
+        if (typeof DeviceOrientationEvent !== 'undefined' && typeof DeviceOrientationEvent.requestPermission === 'function') {
+            try {
+                tiltCheckbox.checked = await DeviceOrientationEvent.requestPermission() === 'granted';
+            } catch (error) {
+                console.warn('tilt unavailable:', error);
+                tiltCheckbox.checked = false;
+            }
+        }
+    });
+
+    // Double click places an attractor, shift + double click a repeller
+    canvas.addEventListener('dblclick', (event) => {
+        const strength = event.shiftKey ? -20.0 : 20.0;
+        sim.add_attractor(event.offsetX, event.offsetY, strength, 60.0);
+    });
+    const toolSelect = document.getElementById('tool');
+    toolSelect.addEventListener('change', () => sim.set_tool(toolSelect.value));
+    const toolRadius = document.getElementById('tool-radius');
+    toolRadius.value = sim.tool_radius;
+    toolRadius.addEventListener('input', () => { sim.tool_radius = parseFloat(toolRadius.value); });
+    const toolStrength = document.getElementById('tool-strength');
+    toolStrength.value = sim.tool_strength;
+    toolStrength.addEventListener('input', () => { sim.tool_strength = parseFloat(toolStrength.value); });
+
+    const forceFieldSelect = document.getElementById('force-field');
+    forceFieldSelect.addEventListener('change', () => {
+        sim.clear_force_fields();
+        switch (forceFieldSelect.value) {
+            case 'wind':
+                sim.add_wind(1.5, 0.0, 2.0);
+                break;
+            case 'vortex':
+                sim.add_vortex(canvas.width / 2, canvas.height / 2, 15.0, canvas.height / 4);
+                break;
+            case 'noise':
+                sim.add_noise(8.0, canvas.height / 6, 2.0);
+                break;
+        }
+    });
+
+    // Keyframes are [time, x, y, angle, ...] in seconds of simulated time and canvas pixels
+    const sceneSelect = document.getElementById('scene');
+    sceneSelect.addEventListener('change', () => {
+        sim.clear_obstacles();
+        const w = canvas.width;
+        const h = canvas.height;
+        switch (sceneSelect.value) {
+            case 'wave-tank': {
+                const paddle = sim.add_box_obstacle(0.05 * w, 0.6 * h, 0.02 * w, 0.8 * h, 0.0);
+                sim.set_obstacle_keyframes(paddle, new Float32Array([
+                    0.0, 0.05 * w, 0.6 * h, 0.0,
+                    0.6, 0.15 * w, 0.6 * h, 0.0,
+                    1.2, 0.05 * w, 0.6 * h, 0.0,
+                ]));
+                break;
+            }
+            case 'mixer': {
+                const blade = sim.add_box_obstacle(0.5 * w, 0.75 * h, 0.3 * h, 0.03 * h, 0.0);
+                sim.set_obstacle_keyframes(blade, new Float32Array([
+                    0.0, 0.5 * w, 0.75 * h, 0.0,
+                    2.0, 0.5 * w, 0.75 * h, 2.0 * Math.PI,
+                ]));
+                break;
+            }
+        }
+    });
+
+    document.getElementById('clear-attractors').addEventListener('click', () => {
+        sim.clear_attractors();
+    });
+    document.getElementById('reset-gravity').addEventListener('click', () => {
+        tiltCheckbox.checked = false;
+        sim.reset_gravity();
+    });
+
+    // Pause, single step and time scale, from the buttons or the keyboard
+    const pauseButton = document.getElementById('pause-button');
+    const timeScaleElement = document.getElementById('time-scale');
+    const togglePause = () => {
+        if (sim.paused) {
+            sim.resume();
+        } else {
+            sim.pause();
+        }
+        pauseButton.textContent = sim.paused ? 'Resume' : 'Pause';
+    };
+    const setTimeScale = (timeScale) => {
+        sim.time_scale = timeScale;
+        timeScaleElement.textContent = `${sim.time_scale}x`;
+    };
+    pauseButton.addEventListener('click', togglePause);
+    document.getElementById('step-button').addEventListener('click', () => sim.single_step());
+    window.addEventListener('keydown', (event) => {
+        if (event.target instanceof HTMLInputElement || event.target instanceof HTMLSelectElement) {
+            return;
+        }
+        switch (event.key) {
+            case ' ':
+                togglePause();
+                break;
+            case '.':
+            case 'ArrowRight':
+                sim.single_step();
+                break;
+            case '[':
+                setTimeScale(sim.time_scale / 2);
+                break;
+            case ']':
+                setTimeScale(sim.time_scale * 2);
+                break;
+            case '0':
+                setTimeScale(1);
+                break;
+            default:
+                return;
+        }
+        event.preventDefault();
+    });
 }
 
 run();
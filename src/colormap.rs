@@ -0,0 +1,323 @@
+use crate::solver::{Material, Particle, ParticleQuantity, State};
+
+// Colour maps for scalar quantities. `sample` takes a value normalized to [0, 1].
+// Also the particle colouring shared by the WebGL and the software renderer.
+
+pub const BACKGROUND_COLOR: (f32, f32, f32) = (0.4, 0.4, 0.4);
+pub const WATER_COLOR: (f32, f32, f32) = (0.15, 0.45, 0.95);
+pub const OBSTACLE_COLOR: (f32, f32, f32) = (0.8, 0.8, 0.8);
+const DYE_COLOR: (f32, f32, f32) = (1.0, 0.25, 0.35);
+const SAND_COLOR: (f32, f32, f32) = (0.85, 0.7, 0.45);
+const JELLY_COLOR: (f32, f32, f32) = (0.45, 0.85, 0.35);
+const SNOW_COLOR: (f32, f32, f32) = (0.92, 0.95, 1.0);
+const MAX_SPEED: f32 = 4.0;
+const MIN_SPEED_RANGE: f32 = 1.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorMode {
+    Dye, 
+    // Auto-ranged through the selected colour map
+    Quantity(ParticleQuantity), 
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorMap {
+    // Hue ramp from blue to red, the original look of the speed colouring
+    Rainbow, 
+    Viridis, 
+    Magma, 
+    // Blue - white - red, centred at zero for signed quantities
+    Diverging, 
+}
+
+// Stops of the matplotlib maps, linearly interpolated
+const VIRIDIS: [(f32, (f32, f32, f32)); 9] = [
+    (0.0, (0.267, 0.005, 0.329)),
+    (0.125, (0.283, 0.141, 0.458)),
+    (0.25, (0.254, 0.265, 0.530)),
+    (0.375, (0.207, 0.372, 0.553)),
+    (0.5, (0.164, 0.471, 0.558)),
+    (0.625, (0.128, 0.567, 0.551)),
+    (0.75, (0.135, 0.659, 0.518)),
+    (0.875, (0.478, 0.821, 0.3182)),
+    (1.0, (0.993, 0.906, 0.144)),
+];
+
+const MAGMA: [(f32, (f32, f32, f32)); 10] = [
+    (0.0, (0.001, 0.000, 0.014)),
+    (0.125, (0.079, 0.054, 0.212)),
+    (0.25, (0.232, 0.060, 0.438)),
+    (0.375, (0.390, 0.100, 0.502)),
+    (0.5, (0.550, 0.161, 0.506)),
+    (0.625, (0.716, 0.215, 0.475)),
+    (0.75, (0.869, 0.288, 0.409)),
+    (0.875, (0.968, 0.440, 0.360)),
+    (0.94, (0.995, 0.745, 0.503)),
+    (1.0, (0.987, 0.991, 0.750)),
+];
+
+// Moreland's cool to warm map
+const DIVERGING: [(f32, (f32, f32, f32)); 5] = [
+    (0.0, (0.230, 0.299, 0.754)),
+    (0.25, (0.552, 0.690, 0.996)),
+    (0.5, (0.865, 0.865, 0.865)),
+    (0.75, (0.958, 0.604, 0.482)),
+    (1.0, (0.706, 0.016, 0.150)),
+];
+
+impl ColorMap {
+    pub fn sample(self, t: f32) -> (f32, f32, f32) {
+        let t = if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) };
+        match self {
+            ColorMap::Rainbow => {
+                let (r, g, b, _) = hsv_to_rgb((1.0 - t) * 0.7, 1.0, 1.0);
+                (r, g, b)
+            }
+            ColorMap::Viridis => interpolate(&VIRIDIS, t), 
+            ColorMap::Magma => interpolate(&MAGMA, t), 
+            ColorMap::Diverging => interpolate(&DIVERGING, t), 
+        }
+    }
+
+    pub fn is_diverging(self) -> bool {
+        self == ColorMap::Diverging
+    }
+}
+
+fn interpolate(stops: &[(f32, (f32, f32, f32))], t: f32) -> (f32, f32, f32) {
+    let next = stops.iter().position(|(s, _)| *s >= t).unwrap_or(stops.len() - 1).max(1);
+    let (t0, a) = stops[next - 1];
+    let (t1, b) = stops[next];
+    let s = ((t - t0) / (t1 - t0)).clamp(0.0, 1.0);
+    (a.0 + (b.0 - a.0) * s, a.1 + (b.1 - a.1) * s, a.2 + (b.2 - a.2) * s)
+}
+
+pub fn hsv_to_rgb(h: f32, s: f32, v: f32) -> (f32, f32, f32, f32) {
+    let i = (h * 6.0).floor() as u32;
+    let f = h * 6.0 - i as f32;
+    let p = v * (1.0 - s);
+    let q = v * (1.0 - f * s);
+    let t = v * (1.0 - (1.0 - f) * s);
+
+    let (mut r, mut g, mut b) = (0.0, 0.0, 0.0);
+
+    match i % 6 {
+        0 => { r = v; g = t; b = p; }
+        1 => { r = q; g = v; b = p; }
+        2 => { r = p; g = v; b = t; }
+        3 => { r = p; g = q; b = v; }
+        4 => { r = t; g = p; b = v; }
+        5 => { r = v; g = p; b = q; }
+        _ => {}
+    }
+
+    (r, g, b, 1.0)
+}
+
+// Value range mapped onto the colour map
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    pub min: f32, 
+    pub max: f32, 
+}
+
+// Outliers beyond these percentiles saturate instead of squashing the rest of the range
+const LOWER_PERCENTILE: f32 = 0.02;
+const UPPER_PERCENTILE: f32 = 0.98;
+
+impl Range {
+    // Auto-ranging over the current values. `from_zero` pins the lower end at zero (magnitudes),
+    // `symmetric` centres the range at zero (for the diverging map). The range spans at least `min_span`.
+    pub fn fit(values: &[f32], from_zero: bool, symmetric: bool, min_span: f32) -> Range {
+        Range::fit_with(values, &mut Vec::new(), from_zero, symmetric, min_span)
+    }
+
+    // Like `fit`, sorting in `scratch` so that a buffer kept over the frames is reused
+    pub fn fit_with(values: &[f32], scratch: &mut Vec<f32>, from_zero: bool, symmetric: bool, min_span: f32) -> Range {
+        scratch.clear();
+        scratch.extend(values.iter().copied().filter(|v| v.is_finite()));
+        let values = scratch;
+        if values.is_empty() {
+            return Range { min: 0.0, max: min_span.max(f32::EPSILON) };
+        }
+        if symmetric {
+            values.iter_mut().for_each(|v| *v = v.abs());
+            let m = percentile(values, UPPER_PERCENTILE).max(0.5 * min_span).max(f32::EPSILON);
+            return Range { min: -m, max: m };
+        }
+        let max = percentile(values, UPPER_PERCENTILE);
+        let min = if from_zero { 0.0 } else { percentile(values, LOWER_PERCENTILE) };
+        // Relative to the values, a span of EPSILON vanishes in `min + span` away from zero
+        let span = (max - min).max(min_span).max(f32::EPSILON * min.abs().max(1.0));
+        Range { min, max: min + span }
+    }
+
+    pub fn normalize(&self, value: f32) -> f32 {
+        (value - self.min) / (self.max - self.min)
+    }
+}
+
+fn percentile(values: &mut [f32], p: f32) -> f32 {
+    let k = ((values.len() - 1) as f32 * p).round() as usize;
+    *values.select_nth_unstable_by(k, |a, b| a.total_cmp(b)).1
+}
+
+// Colours of all particles for one frame, the quantity is evaluated and ranged once.
+// Kept over the frames and refreshed with `update`, which reuses the buffers.
+pub struct ParticleColors {
+    mode: ColorMode, 
+    map: ColorMap, 
+    values: Vec<f32>, 
+    // Working copy of the values for the range fit
+    scratch: Vec<f32>, 
+    range: Range, 
+}
+
+impl ParticleColors {
+    pub fn new(state: &State, mode: ColorMode, map: ColorMap) -> Self {
+        let mut colors = ParticleColors { mode, map, values: Vec::new(), scratch: Vec::new(), range: Range { min: 0.0, max: 1.0 } };
+        colors.update(state, mode, map);
+        colors
+    }
+
+    pub fn update(&mut self, state: &State, mode: ColorMode, map: ColorMap) {
+        self.mode = mode;
+        self.map = map;
+        match mode {
+            ColorMode::Quantity(quantity) => {
+                state.particle_quantity_into(quantity, &mut self.values);
+                self.range = get_color_range(quantity, &self.values, &mut self.scratch, map);
+            }
+            ColorMode::Dye => {
+                self.values.clear();
+                self.range = Range { min: 0.0, max: 1.0 };
+            }
+        }
+    }
+
+    // Colour of the `i`-th particle of the state
+    pub fn color(&self, i: usize, particle: &Particle) -> (f32, f32, f32) {
+        let speed = particle.velocity.length();
+        let (r, g, b, _) = match self.mode {
+            ColorMode::Dye => get_color_of_material(particle.material, speed)
+                .unwrap_or_else(|| get_color_by_dye(speed, particle.dye)), 
+            // Solids keep their colour in the default speed view
+            ColorMode::Quantity(ParticleQuantity::Speed) => get_color_of_material(particle.material, speed)
+                .unwrap_or_else(|| self.sample(i)), 
+            ColorMode::Quantity(_) => self.sample(i), 
+        };
+        (r, g, b)
+    }
+
+    fn sample(&self, i: usize) -> (f32, f32, f32, f32) {
+        let (r, g, b) = self.map.sample(self.range.normalize(self.values[i]));
+        (r, g, b, 1.0)
+    }
+}
+
+// Speed starts at zero and doesn't stretch a fluid at rest over the whole map.
+// Signed quantities are centred at zero on the diverging map.
+fn get_color_range(quantity: ParticleQuantity, values: &[f32], scratch: &mut Vec<f32>, color_map: ColorMap) -> Range {
+    let (from_zero, min_span) = match quantity {
+        ParticleQuantity::Speed => (true, MIN_SPEED_RANGE), 
+        ParticleQuantity::Force => (true, 0.0), 
+        _ => (false, 0.0), 
+    };
+    Range::fit_with(values, scratch, from_zero, color_map.is_diverging(), min_span)
+}
+
+fn get_color_by_dye(speed: f32, dye: f32) -> (f32, f32, f32, f32) {
+    let lerp = |a: f32, b: f32| a + (b - a) * dye;
+    // Faster particles are drawn slightly brighter so that the flow stays visible
+    let brightness = 0.75 + 0.25 * (speed.abs() / MAX_SPEED).min(1.0);
+    let r = lerp(WATER_COLOR.0, DYE_COLOR.0) * brightness;
+    let g = lerp(WATER_COLOR.1, DYE_COLOR.1) * brightness;
+    let b = lerp(WATER_COLOR.2, DYE_COLOR.2) * brightness;
+    (r, g, b, 1.0)
+}
+
+fn get_color_of_material(material: Material, speed: f32) -> Option<(f32, f32, f32, f32)> {
+    match material {
+        Material::Sand => Some(get_color_of_solid(SAND_COLOR, speed)), 
+        Material::Jelly => Some(get_color_of_solid(JELLY_COLOR, speed)), 
+        Material::Snow => Some(get_color_of_solid(SNOW_COLOR, speed)), 
+        Material::Liquid => None, 
+    }
+}
+
+fn get_color_of_solid(color: (f32, f32, f32), speed: f32) -> (f32, f32, f32, f32) {
+    // Moving parts are drawn lighter so that avalanches and wobbling stand out
+    let t = 0.8 + 0.2 * (speed.abs() / MAX_SPEED).min(1.0);
+    (color.0 * t, color.1 * t, color.2 * t, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0, 1, ..., 100
+    fn ramp() -> Vec<f32> {
+        (0..=100).map(|i| i as f32).collect()
+    }
+
+    #[test]
+    fn fit_cuts_off_the_outliers() {
+        let mut values = ramp();
+        values.push(1e6);
+        values.push(-1e6);
+        let range = Range::fit(&values, false, false, 0.0);
+        assert!(range.min >= 0.0 && range.min < 5.0, "{:?}", range);
+        assert!(range.max > 95.0 && range.max <= 100.0, "{:?}", range);
+        assert!(range.normalize(1e6) > 1.0 && range.normalize(-1e6) < 0.0);
+    }
+
+    #[test]
+    fn fit_from_zero() {
+        let values: Vec<f32> = ramp().iter().map(|v| v + 50.0).collect();
+        let range = Range::fit(&values, true, false, 0.0);
+        assert_eq!(range.min, 0.0);
+        assert_eq!(range.normalize(0.0), 0.0);
+    }
+
+    #[test]
+    fn fit_symmetric() {
+        let values: Vec<f32> = ramp().iter().map(|v| v - 20.0).collect();
+        let range = Range::fit(&values, false, true, 0.0);
+        assert_eq!(range.min, -range.max);
+        assert!(range.max > 70.0 && range.max <= 80.0, "{:?}", range);
+        assert_eq!(range.normalize(0.0), 0.5);
+    }
+
+    #[test]
+    fn fit_spans_at_least_min_span() {
+        // A fluid at rest isn't stretched over the whole map
+        let range = Range::fit(&[0.01; 10], true, false, MIN_SPEED_RANGE);
+        assert_eq!((range.min, range.max), (0.0, MIN_SPEED_RANGE));
+        let range = Range::fit(&[0.01; 10], false, true, 2.0);
+        assert_eq!((range.min, range.max), (-1.0, 1.0));
+        // Constant values without a minimum span still give a finite normalization
+        let range = Range::fit(&[3.0; 10], false, false, 0.0);
+        assert!(range.max > range.min && range.normalize(3.0).is_finite(), "{:?}", range);
+    }
+
+    #[test]
+    fn fit_ignores_non_finite_values() {
+        let range = Range::fit(&[f32::NAN, 1.0, f32::INFINITY, 2.0, f32::NEG_INFINITY], false, false, 0.0);
+        assert_eq!((range.min, range.max), (1.0, 2.0));
+        let range = Range::fit(&[f32::NAN], false, false, 0.5);
+        assert_eq!((range.min, range.max), (0.0, 0.5));
+        let range = Range::fit(&[], false, false, 0.0);
+        assert!(range.max > range.min, "{:?}", range);
+    }
+
+    #[test]
+    fn fit_with_reuses_the_scratch_buffer() {
+        let values = ramp();
+        let mut scratch = Vec::with_capacity(values.len());
+        let capacity = scratch.capacity();
+        for _ in 0..3 {
+            assert_eq!(Range::fit_with(&values, &mut scratch, false, false, 0.0), Range::fit(&values, false, false, 0.0));
+        }
+        assert_eq!(scratch.capacity(), capacity);
+    }
+}
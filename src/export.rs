@@ -0,0 +1,188 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::colormap::{ColorMap, ColorMode};
+use crate::raster::{self, Image};
+use crate::solver::{ParticleQuantity, State};
+
+// Records a headless run frame by frame with the software renderer,
+// as a numbered PNG sequence or a looping animated GIF.
+
+#[derive(Clone, Copy, Debug)]
+pub struct ExportOptions {
+    pub width: u32, 
+    pub height: u32, 
+    // Number of frames written
+    pub frames: u32, 
+    // Simulation frames between two written frames, 1 writes every frame
+    pub frame_skip: u32, 
+    pub color_mode: ColorMode, 
+    pub color_map: ColorMap, 
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions {
+            width: 640,
+            height: 360,
+            frames: 200,
+            frame_skip: 2,
+            color_mode: ColorMode::Quantity(ParticleQuantity::Speed),
+            color_map: ColorMap::Rainbow,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(std::io::Error), 
+    Png(png::EncodingError), 
+    Gif(gif::EncodingError), 
+    // Zero, or too large for the format (GIF has 16 bit dimensions)
+    InvalidSize { width: u32, height: u32 }, 
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportError::Io(error) => write!(f, "io error: {}", error),
+            ExportError::Png(error) => write!(f, "png error: {}", error),
+            ExportError::Gif(error) => write!(f, "gif error: {}", error),
+            ExportError::InvalidSize { width, height } => write!(f, "invalid image size: {}x{}", width, height),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<std::io::Error> for ExportError {
+    fn from(error: std::io::Error) -> Self {
+        ExportError::Io(error)
+    }
+}
+
+impl From<png::EncodingError> for ExportError {
+    fn from(error: png::EncodingError) -> Self {
+        ExportError::Png(error)
+    }
+}
+
+impl From<gif::EncodingError> for ExportError {
+    fn from(error: gif::EncodingError) -> Self {
+        ExportError::Gif(error)
+    }
+}
+
+// Renders the current state, then steps `frame_skip` frames, `frames` times.
+// `on_frame` gets the index of the written frame and its image.
+pub fn record<F>(state: &mut State, options: &ExportOptions, mut on_frame: F) -> Result<(), ExportError>
+where
+    F: FnMut(u32, Image) -> Result<(), ExportError>,
+{
+    if options.width == 0 || options.height == 0 {
+        return Err(ExportError::InvalidSize { width: options.width, height: options.height });
+    }
+    for frame in 0..options.frames {
+        let image = raster::render(state, options.width, options.height, options.color_mode, options.color_map);
+        on_frame(frame, image)?;
+        for _ in 0..options.frame_skip.max(1) {
+            state.update(&[]);
+        }
+    }
+    Ok(())
+}
+
+// Writes `frame_00000.png`, `frame_00001.png`, ... into `directory`, which is created if needed
+pub fn export_png_sequence<P: AsRef<Path>>(state: &mut State, options: &ExportOptions, directory: P) -> Result<(), ExportError> {
+    let directory = directory.as_ref();
+    std::fs::create_dir_all(directory)?;
+    record(state, options, |frame, image|{
+        image.save_png(directory.join(format!("frame_{:05}.png", frame)))?;
+        Ok(())
+    })
+}
+
+// Palette quantization speed of the GIF encoder, 1 (best) to 30 (fastest)
+const GIF_QUANTIZATION_SPEED: i32 = 10;
+// Most viewers play shorter frame delays at 10 cs
+const MIN_GIF_DELAY: u16 = 2;
+
+// Plays back in real time of the simulation, as far as the centisecond frame delays of GIF allow
+pub fn export_gif<P: AsRef<Path>>(state: &mut State, options: &ExportOptions, path: P) -> Result<(), ExportError> {
+    let invalid_size = || ExportError::InvalidSize { width: options.width, height: options.height };
+    let width = u16::try_from(options.width).map_err(|_| invalid_size())?;
+    let height = u16::try_from(options.height).map_err(|_| invalid_size())?;
+    if width == 0 || height == 0 {
+        return Err(invalid_size());
+    }
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = gif::Encoder::new(file, width, height, &[])?;
+    encoder.set_repeat(gif::Repeat::Infinite)?;
+    let delay = ((options.frame_skip.max(1) as f32 * state.frame_time() * 100.0).round() as u16).max(MIN_GIF_DELAY);
+    record(state, options, |_, mut image|{
+        let mut frame = gif::Frame::from_rgba_speed(width, height, &mut image.pixels, GIF_QUANTIZATION_SPEED);
+        frame.delay = delay;
+        encoder.write_frame(&frame)?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::Scene;
+
+    fn state() -> State {
+        State::new(&Scene { num_particles: 50, field_width: 0.4, field_height: 0.2, ..Default::default() })
+    }
+
+    fn options(width: u32, height: u32) -> ExportOptions {
+        ExportOptions { width, height, frames: 3, frame_skip: 2, ..ExportOptions::default() }
+    }
+
+    fn is_invalid_size(result: Result<(), ExportError>, size: (u32, u32)) -> bool {
+        matches!(result, Err(ExportError::InvalidSize { width, height }) if (width, height) == size)
+    }
+
+    #[test]
+    fn record_writes_every_frame() {
+        let mut state = state();
+        let mut frames = Vec::new();
+        record(&mut state, &options(32, 16), |frame, image|{
+            assert_eq!((image.width, image.height), (32, 16));
+            frames.push(frame);
+            Ok(())
+        }).unwrap();
+        assert_eq!(frames, vec![0, 1, 2]);
+        assert!((state.diagnostics().time - 6.0 * state.frame_time()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn zero_size_is_rejected_before_stepping() {
+        for &size in &[(0, 16), (32, 0), (0, 0)] {
+            let mut state = state();
+            let result = record(&mut state, &options(size.0, size.1), |_, _| panic!("no frame expected"));
+            assert!(is_invalid_size(result, size));
+            assert_eq!(state.diagnostics().time, 0.0);
+        }
+    }
+
+    #[test]
+    fn gif_size_is_checked_before_creating_the_file() {
+        let path = std::env::temp_dir().join(format!("rust-fluid-invalid-{}.gif", std::process::id()));
+        for &size in &[(0, 16), (32, 0), (70000, 16), (32, 70000)] {
+            let mut state = state();
+            assert!(is_invalid_size(export_gif(&mut state, &options(size.0, size.1), &path), size));
+            assert!(!path.exists());
+        }
+    }
+
+    #[test]
+    fn invalid_size_message() {
+        let error = ExportError::InvalidSize { width: 0, height: 360 };
+        assert_eq!(error.to_string(), "invalid image size: 0x360");
+    }
+}
@@ -0,0 +1,293 @@
+use glam::Vec2;
+use rayon::prelude::*;
+
+use crate::solver::{Field, Particle};
+
+// FLIP/PIC solver on a staggered (MAC) grid, following "Ten Minute Physics" by Matthias Müller.
+// Particle velocities are transferred to the grid, made divergence free by a Gauss-Seidel
+// pressure projection and transferred back as a blend of the PIC and FLIP updates.
+
+pub const FLIP_DT: f32 = 0.0025;
+pub const FLIP_STEPS: u32 = 4;
+pub const FLIP_RATIO: f32 = 0.9;
+const PRESSURE_ITERATIONS: u32 = 50;
+const OVER_RELAXATION: f32 = 1.9;
+const DRIFT_STIFFNESS: f32 = 1.0;
+
+#[derive(Clone, Copy, PartialEq)]
+enum CellType {
+    Fluid, 
+    Air, 
+    Solid, 
+}
+
+pub struct Flip {
+    nx: usize, 
+    ny: usize, 
+    dx: f32, 
+    // Horizontal velocities live on the vertical faces ((nx + 1) * ny) and
+    // vertical velocities on the horizontal faces (nx * (ny + 1))
+    u: Vec<f32>, 
+    v: Vec<f32>, 
+    prev_u: Vec<f32>, 
+    prev_v: Vec<f32>, 
+    weight_u: Vec<f32>, 
+    weight_v: Vec<f32>, 
+    cell_type: Vec<CellType>, 
+    particle_density: Vec<f32>, 
+    rest_density: f32, 
+    // 0.0 is pure PIC (stable, dissipative), 1.0 is pure FLIP (lively, noisy)
+    pub flip_ratio: f32, 
+}
+
+impl Flip {
+    pub fn new(field: &Field, dx: f32) -> Self {
+        let nx = (field.width / dx).ceil() as usize;
+        let ny = (field.height / dx).ceil() as usize;
+        Flip {
+            nx,
+            ny,
+            dx,
+            u: vec![0.0; (nx + 1) * ny],
+            v: vec![0.0; nx * (ny + 1)],
+            prev_u: vec![0.0; (nx + 1) * ny],
+            prev_v: vec![0.0; nx * (ny + 1)],
+            weight_u: vec![0.0; (nx + 1) * ny],
+            weight_v: vec![0.0; nx * (ny + 1)],
+            cell_type: vec![CellType::Air; nx * ny],
+            particle_density: vec![0.0; nx * ny],
+            rest_density: 0.0,
+            flip_ratio: FLIP_RATIO,
+        }
+    }
+
+    // New grid for the field, keeping the reference density and the blend
+    pub fn resize(&mut self, field: &Field) {
+        *self = Flip { rest_density: self.rest_density, flip_ratio: self.flip_ratio, ..Flip::new(field, self.dx) };
+    }
+
+    pub fn step(&mut self, particles: &mut [Particle], field: &Field, gravity: Vec2, dt: f32) {
+        self.classify_cells(particles);
+        self.update_particle_density(particles);
+        self.particle_to_grid(particles);
+        self.prev_u.copy_from_slice(&self.u);
+        self.prev_v.copy_from_slice(&self.v);
+        self.apply_gravity(gravity, dt);
+        self.enforce_boundaries();
+        self.solve_pressure();
+        self.grid_to_particle(particles);
+        self.advect(particles, field, dt);
+    }
+
+    fn classify_cells(&mut self, particles: &[Particle]) {
+        let (nx, ny) = (self.nx, self.ny);
+        for j in 0..ny {
+            for i in 0..nx {
+                let solid = i == 0 || j == 0 || i == nx - 1 || j == ny - 1;
+                self.cell_type[j * nx + i] = if solid { CellType::Solid } else { CellType::Air };
+            }
+        }
+        for particle in particles {
+            let id = self.cell_id(particle.position);
+            if self.cell_type[id] == CellType::Air {
+                self.cell_type[id] = CellType::Fluid;
+            }
+        }
+    }
+
+    fn update_particle_density(&mut self, particles: &[Particle]) {
+        self.particle_density.iter_mut().for_each(|d| *d = 0.0);
+        let offset = Vec2::splat(0.5 * self.dx);
+        for particle in particles {
+            let (i, j, t) = self.bilinear(particle.position - offset, self.nx, self.ny);
+            let nx = self.nx;
+            for (di, dj, w) in corner_weights(t) {
+                self.particle_density[(j + dj) * nx + i + di] += w;
+            }
+        }
+
+        // The density of the first frame is the reference for the drift compensation
+        if self.rest_density == 0.0 {
+            let (sum, count) = self.particle_density.iter()
+                .zip(self.cell_type.iter())
+                .filter(|(_, cell)| **cell == CellType::Fluid)
+                .fold((0.0, 0), |(sum, count), (d, _)| (sum + d, count + 1));
+            if count > 0 {
+                self.rest_density = sum / count as f32;
+            }
+        }
+    }
+
+    fn particle_to_grid(&mut self, particles: &[Particle]) {
+        self.u.iter_mut().for_each(|x| *x = 0.0);
+        self.v.iter_mut().for_each(|x| *x = 0.0);
+        self.weight_u.iter_mut().for_each(|x| *x = 0.0);
+        self.weight_v.iter_mut().for_each(|x| *x = 0.0);
+
+        let (nx, ny) = (self.nx, self.ny);
+        let offset_u = Vec2::new(0.0, 0.5 * self.dx);
+        let offset_v = Vec2::new(0.5 * self.dx, 0.0);
+        for particle in particles {
+            let (i, j, t) = self.bilinear(particle.position - offset_u, nx + 1, ny);
+            for (di, dj, w) in corner_weights(t) {
+                let id = (j + dj) * (nx + 1) + i + di;
+                self.u[id] += w * particle.velocity.x;
+                self.weight_u[id] += w;
+            }
+            let (i, j, t) = self.bilinear(particle.position - offset_v, nx, ny + 1);
+            for (di, dj, w) in corner_weights(t) {
+                let id = (j + dj) * nx + i + di;
+                self.v[id] += w * particle.velocity.y;
+                self.weight_v[id] += w;
+            }
+        }
+
+        self.u.iter_mut().zip(self.weight_u.iter()).for_each(|(x, w)| if *w > 0.0 { *x /= w });
+        self.v.iter_mut().zip(self.weight_v.iter()).for_each(|(x, w)| if *w > 0.0 { *x /= w });
+    }
+
+    fn apply_gravity(&mut self, gravity: Vec2, dt: f32) {
+        self.u.iter_mut().zip(self.weight_u.iter()).for_each(|(x, w)| if *w > 0.0 { *x += gravity.x * dt });
+        self.v.iter_mut().zip(self.weight_v.iter()).for_each(|(x, w)| if *w > 0.0 { *x += gravity.y * dt });
+    }
+
+    // No flow through the faces of solid cells
+    fn enforce_boundaries(&mut self) {
+        let (nx, ny) = (self.nx, self.ny);
+        for j in 0..ny {
+            for i in 0..nx {
+                if self.cell_type[j * nx + i] != CellType::Solid {
+                    continue;
+                }
+                self.u[j * (nx + 1) + i] = 0.0;
+                self.u[j * (nx + 1) + i + 1] = 0.0;
+                self.v[j * nx + i] = 0.0;
+                self.v[(j + 1) * nx + i] = 0.0;
+            }
+        }
+    }
+
+    fn solve_pressure(&mut self) {
+        let (nx, ny) = (self.nx, self.ny);
+        let s = |cell: CellType| if cell == CellType::Solid { 0.0 } else { 1.0 };
+
+        for _ in 0..PRESSURE_ITERATIONS {
+            for j in 1..ny - 1 {
+                for i in 1..nx - 1 {
+                    let id = j * nx + i;
+                    if self.cell_type[id] != CellType::Fluid {
+                        continue;
+                    }
+                    let sx0 = s(self.cell_type[id - 1]);
+                    let sx1 = s(self.cell_type[id + 1]);
+                    let sy0 = s(self.cell_type[id - nx]);
+                    let sy1 = s(self.cell_type[id + nx]);
+                    let s_sum = sx0 + sx1 + sy0 + sy1;
+                    if s_sum == 0.0 {
+                        continue;
+                    }
+
+                    let u0 = j * (nx + 1) + i;
+                    let v0 = j * nx + i;
+                    let mut divergence = self.u[u0 + 1] - self.u[u0] + self.v[v0 + nx] - self.v[v0];
+                    divergence *= OVER_RELAXATION;
+                    // Push particles apart where they have bunched up over time
+                    if self.rest_density > 0.0 {
+                        let compression = self.particle_density[id] - self.rest_density;
+                        if compression > 0.0 {
+                            divergence -= DRIFT_STIFFNESS * compression;
+                        }
+                    }
+
+                    let p = -divergence / s_sum;
+                    self.u[u0] -= sx0 * p;
+                    self.u[u0 + 1] += sx1 * p;
+                    self.v[v0] -= sy0 * p;
+                    self.v[v0 + nx] += sy1 * p;
+                }
+            }
+        }
+    }
+
+    fn grid_to_particle(&self, particles: &mut [Particle]) {
+        let (nx, ny) = (self.nx, self.ny);
+        let offset_u = Vec2::new(0.0, 0.5 * self.dx);
+        let offset_v = Vec2::new(0.5 * self.dx, 0.0);
+        let flip_ratio = self.flip_ratio;
+
+        particles.par_iter_mut().for_each(|particle|{
+            let mut pic = particle.velocity;
+            let mut flip = particle.velocity;
+
+            let (i, j, t) = self.bilinear(particle.position - offset_u, nx + 1, ny);
+            let (mut sum, mut delta, mut weight) = (0.0, 0.0, 0.0);
+            for (di, dj, w) in corner_weights(t) {
+                let id = (j + dj) * (nx + 1) + i + di;
+                // Only faces that received a velocity from a particle carry information
+                if self.weight_u[id] > 0.0 {
+                    sum += w * self.u[id];
+                    delta += w * (self.u[id] - self.prev_u[id]);
+                    weight += w;
+                }
+            }
+            if weight > 0.0 {
+                pic.x = sum / weight;
+                flip.x += delta / weight;
+            }
+
+            let (i, j, t) = self.bilinear(particle.position - offset_v, nx, ny + 1);
+            let (mut sum, mut delta, mut weight) = (0.0, 0.0, 0.0);
+            for (di, dj, w) in corner_weights(t) {
+                let id = (j + dj) * nx + i + di;
+                if self.weight_v[id] > 0.0 {
+                    sum += w * self.v[id];
+                    delta += w * (self.v[id] - self.prev_v[id]);
+                    weight += w;
+                }
+            }
+            if weight > 0.0 {
+                pic.y = sum / weight;
+                flip.y += delta / weight;
+            }
+
+            particle.velocity = (1.0 - flip_ratio) * pic + flip_ratio * flip;
+        });
+    }
+
+    fn advect(&self, particles: &mut [Particle], field: &Field, dt: f32) {
+        // Keep the particles out of the solid border cells
+        let min = Vec2::splat(self.dx + 1e-4);
+        let max = Vec2::new(field.width.min((self.nx - 1) as f32 * self.dx), field.height.min((self.ny - 1) as f32 * self.dx)) - 1e-4;
+        particles.par_iter_mut().for_each(|particle|{
+            particle.position += particle.velocity * dt;
+            if particle.position.x < min.x { particle.position.x = min.x; particle.velocity.x = 0.0; }
+            if particle.position.x > max.x { particle.position.x = max.x; particle.velocity.x = 0.0; }
+            if particle.position.y < min.y { particle.position.y = min.y; particle.velocity.y = 0.0; }
+            if particle.position.y > max.y { particle.position.y = max.y; particle.velocity.y = 0.0; }
+        });
+    }
+
+    fn cell_id(&self, position: Vec2) -> usize {
+        let i = ((position.x / self.dx) as usize).min(self.nx - 1);
+        let j = ((position.y / self.dx) as usize).min(self.ny - 1);
+        j * self.nx + i
+    }
+
+    // Lower-left sample and the fractional offset inside the sample grid of the given size
+    fn bilinear(&self, position: Vec2, sx: usize, sy: usize) -> (usize, usize, Vec2) {
+        let p = (position / self.dx).max(Vec2::ZERO);
+        let i = (p.x as usize).min(sx - 2);
+        let j = (p.y as usize).min(sy - 2);
+        let t = (p - Vec2::new(i as f32, j as f32)).clamp(Vec2::ZERO, Vec2::ONE);
+        (i, j, t)
+    }
+}
+
+fn corner_weights(t: Vec2) -> [(usize, usize, f32); 4] {
+    [
+        (0, 0, (1.0 - t.x) * (1.0 - t.y)),
+        (1, 0, t.x * (1.0 - t.y)),
+        (0, 1, (1.0 - t.x) * t.y),
+        (1, 1, t.x * t.y),
+    ]
+}
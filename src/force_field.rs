@@ -0,0 +1,102 @@
+use glam::Vec2;
+
+// External forces evaluated per particle every substep, on top of gravity and the attractors.
+// All fields return an acceleration (m/s^2) so that they act the same on every material.
+// The built-in fields have no effect with a zero radius or scale.
+pub trait ForceField: Send + Sync {
+    fn acceleration(&self, position: Vec2, velocity: Vec2, time: f32) -> Vec2;
+}
+
+// Drags particles towards the wind velocity
+pub struct Wind {
+    pub velocity: Vec2, 
+    pub drag: f32, 
+}
+
+// Swirl around `center`, counter-clockwise for positive strength. Strongest at `radius`.
+pub struct Vortex {
+    pub center: Vec2, 
+    pub strength: f32, 
+    pub radius: f32, 
+}
+
+// Push away from (positive strength) or pull towards `center`, fading out linearly up to `radius`
+pub struct Radial {
+    pub center: Vec2, 
+    pub strength: f32, 
+    pub radius: f32, 
+}
+
+// Divergence free turbulence: the curl of a few travelling sine waves
+pub struct Noise {
+    pub amplitude: f32, 
+    // Length of the eddies
+    pub scale: f32, 
+    pub speed: f32, 
+}
+
+// Arbitrary field from Rust code, e.g. `ClosureField(|p, _, _| Vec2::new(-p.y, p.x))`
+pub struct ClosureField<F>(pub F)
+where F: Fn(Vec2, Vec2, f32) -> Vec2 + Send + Sync;
+
+impl ForceField for Wind {
+    fn acceleration(&self, _position: Vec2, velocity: Vec2, _time: f32) -> Vec2 {
+        self.drag * (self.velocity - velocity)
+    }
+}
+
+impl ForceField for Vortex {
+    fn acceleration(&self, position: Vec2, _velocity: Vec2, _time: f32) -> Vec2 {
+        if self.radius <= 0.0 {
+            return Vec2::new(0.0, 0.0);
+        }
+        let d = position - self.center;
+        let r2 = d.length_squared();
+        let radius2 = self.radius * self.radius;
+        let tangent = d.perp().normalize_or_zero();
+        self.strength * 2.0 * r2.sqrt() * self.radius / (r2 + radius2) * tangent
+    }
+}
+
+impl ForceField for Radial {
+    fn acceleration(&self, position: Vec2, _velocity: Vec2, _time: f32) -> Vec2 {
+        if self.radius <= 0.0 {
+            return Vec2::new(0.0, 0.0);
+        }
+        let d = position - self.center;
+        let falloff = (1.0 - d.length() / self.radius).max(0.0);
+        self.strength * falloff * d.normalize_or_zero()
+    }
+}
+
+const NOISE_WAVES: [(f32, f32, f32); 4] = [
+    // (direction, angular speed, phase)
+    (0.3, 1.0, 0.0),
+    (1.9, -1.3, 1.7),
+    (3.4, 0.7, 4.1),
+    (4.8, -0.9, 2.6),
+];
+
+impl ForceField for Noise {
+    fn acceleration(&self, position: Vec2, _velocity: Vec2, time: f32) -> Vec2 {
+        // Eddies of zero length have infinite wave numbers
+        if self.scale <= 0.0 {
+            return Vec2::new(0.0, 0.0);
+        }
+        let mut grad = Vec2::new(0.0, 0.0);
+        for (angle, omega, phase) in NOISE_WAVES {
+            let k = Vec2::from_angle(angle) / self.scale;
+            grad += (k.dot(position) + omega * self.speed * time + phase).cos() * k;
+        }
+        // Curl of the stream function, normalised so that `amplitude` is the typical magnitude
+        self.amplitude * self.scale / NOISE_WAVES.len() as f32 * Vec2::new(grad.y, -grad.x)
+    }
+}
+
+impl<F> ForceField for ClosureField<F>
+where F: Fn(Vec2, Vec2, f32) -> Vec2 + Send + Sync
+{
+    fn acceleration(&self, position: Vec2, velocity: Vec2, time: f32) -> Vec2 {
+        (self.0)(position, velocity, time)
+    }
+}
@@ -0,0 +1,114 @@
+use std::ops::{Deref, DerefMut};
+
+// List of scene items that JS adds and removes by id.
+// Ids stay valid when other items are removed and are never reused, unlike the indices.
+// Reads as a slice in item order, so the solver iterates it like a Vec.
+#[derive(Clone, Debug)]
+pub struct IdList<T> {
+    items: Vec<T>, 
+    ids: Vec<u32>, 
+    next_id: u32, 
+}
+
+impl<T> Default for IdList<T> {
+    fn default() -> Self {
+        IdList { items: Vec::new(), ids: Vec::new(), next_id: 0 }
+    }
+}
+
+impl<T> IdList<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the id of the item
+    pub fn push(&mut self, item: T) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.items.push(item);
+        self.ids.push(id);
+        id
+    }
+
+    pub fn get(&self, id: u32) -> Option<&T> {
+        self.position(id).map(|i| &self.items[i])
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut T> {
+        self.position(id).map(move |i| &mut self.items[i])
+    }
+
+    // Unknown or already removed ids are ignored
+    pub fn remove(&mut self, id: u32) -> Option<T> {
+        let i = self.position(id)?;
+        self.ids.remove(i);
+        Some(self.items.remove(i))
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.ids.clear();
+    }
+
+    // Ids ascend with the insertion order
+    fn position(&self, id: u32) -> Option<usize> {
+        self.ids.binary_search(&id).ok()
+    }
+}
+
+impl<T> Deref for IdList<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.items
+    }
+}
+
+impl<T> DerefMut for IdList<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.items
+    }
+}
+
+impl<'a, T> IntoIterator for &'a IdList<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_survive_removal() {
+        let mut list = IdList::new();
+        let a = list.push('a');
+        let b = list.push('b');
+        let c = list.push('c');
+        assert_eq!(list.remove(a), Some('a'));
+        assert_eq!(list.get(b), Some(&'b'));
+        assert_eq!(list.get(c), Some(&'c'));
+        assert_eq!(&list[..], &['b', 'c']);
+        // Removed ids stay unknown and aren't handed out again
+        assert_eq!(list.remove(a), None);
+        assert_eq!(list.get(a), None);
+        let d = list.push('d');
+        assert!(d != a && d != b && d != c);
+        *list.get_mut(d).unwrap() = 'e';
+        assert_eq!(list.iter().collect::<String>(), "bce");
+    }
+
+    #[test]
+    fn clear_keeps_counting() {
+        let mut list = IdList::new();
+        let a = list.push(1);
+        list.clear();
+        assert!(list.is_empty());
+        assert_eq!(list.get(a), None);
+        assert_ne!(list.push(2), a);
+    }
+}
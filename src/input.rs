@@ -0,0 +1,185 @@
+// Pointer input, kept free of web-sys so that it can be driven from anywhere.
+// Event listeners only push `InputEvent`s into an `InputQueue`, and once per frame the simulation
+// drains the queue into an `InputState` that tracks every active contact (mouse, pen or finger).
+
+// Weight of the newest frame in the smoothed pointer velocity
+const VELOCITY_SMOOTHING: f32 = 0.5;
+
+// Positions are in canvas pixels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputEvent {
+    Down { id: i32, x: f32, y: f32, paint: bool }, 
+    Move { id: i32, x: f32, y: f32 }, 
+    Up { id: i32 }, 
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Contact {
+    pub id: i32, 
+    pub x: f32, 
+    pub y: f32, 
+    // Position at the end of the previous frame
+    pub last_x: f32, 
+    pub last_y: f32, 
+    // Smoothed velocity in canvas pixels per frame
+    pub vx: f32, 
+    pub vy: f32, 
+    // Paints dye instead of applying the tool
+    pub paint: bool, 
+}
+
+#[derive(Default, Debug)]
+pub struct InputQueue {
+    events: Vec<InputEvent>, 
+}
+
+#[derive(Default, Debug)]
+pub struct InputState {
+    contacts: Vec<Contact>, 
+}
+
+impl InputQueue {
+    pub fn push(&mut self, event: InputEvent) {
+        self.events.push(event);
+    }
+
+    pub fn drain(&mut self) -> Vec<InputEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+impl InputState {
+    pub fn apply(&mut self, events: &[InputEvent]) {
+        for event in events {
+            match *event {
+                InputEvent::Down { id, x, y, paint } => {
+                    self.contacts.retain(|contact| contact.id != id);
+                    self.contacts.push(Contact { id, x, y, last_x: x, last_y: y, vx: 0.0, vy: 0.0, paint });
+                }
+                InputEvent::Move { id, x, y } => {
+                    if let Some(contact) = self.contacts.iter_mut().find(|contact| contact.id == id) {
+                        contact.x = x;
+                        contact.y = y;
+                    }
+                }
+                InputEvent::Up { id } => {
+                    self.contacts.retain(|contact| contact.id != id);
+                }
+            }
+        }
+    }
+
+    pub fn contacts(&self) -> &[Contact] {
+        &self.contacts
+    }
+
+    // Called once per frame after `apply`. Events don't arrive in step with the frames,
+    // so the per-frame motion is smoothed to keep a steady pointer velocity.
+    pub fn update_velocities(&mut self) {
+        for contact in self.contacts.iter_mut() {
+            contact.vx += VELOCITY_SMOOTHING * ((contact.x - contact.last_x) - contact.vx);
+            contact.vy += VELOCITY_SMOOTHING * ((contact.y - contact.last_y) - contact.vy);
+            contact.last_x = contact.x;
+            contact.last_y = contact.y;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(state: &mut InputState, queue: &mut InputQueue) {
+        state.apply(&queue.drain());
+        state.update_velocities();
+    }
+
+    #[test]
+    fn press_move_release() {
+        let mut queue = InputQueue::default();
+        let mut state = InputState::default();
+        queue.push(InputEvent::Down { id: 1, x: 10.0, y: 20.0, paint: false });
+        apply(&mut state, &mut queue);
+        assert_eq!(state.contacts().len(), 1);
+        assert_eq!((state.contacts()[0].vx, state.contacts()[0].vy), (0.0, 0.0));
+
+        queue.push(InputEvent::Move { id: 1, x: 14.0, y: 18.0 });
+        apply(&mut state, &mut queue);
+        let contact = state.contacts()[0];
+        assert_eq!((contact.x, contact.y), (14.0, 18.0));
+        assert_eq!((contact.last_x, contact.last_y), (14.0, 18.0));
+
+        queue.push(InputEvent::Up { id: 1 });
+        apply(&mut state, &mut queue);
+        assert!(state.contacts().is_empty());
+        assert!(queue.drain().is_empty());
+    }
+
+    #[test]
+    fn events_apply_in_order() {
+        let mut state = InputState::default();
+        // Released and pressed again within one frame: a fresh contact at the new position
+        state.apply(&[
+            InputEvent::Down { id: 1, x: 0.0, y: 0.0, paint: false },
+            InputEvent::Move { id: 1, x: 5.0, y: 0.0 },
+            InputEvent::Up { id: 1 },
+            InputEvent::Down { id: 1, x: 50.0, y: 50.0, paint: true },
+        ]);
+        assert_eq!(state.contacts().len(), 1);
+        let contact = state.contacts()[0];
+        assert_eq!((contact.x, contact.last_x, contact.paint), (50.0, 50.0, true));
+
+        // Moves before the press and after the release are ignored
+        let mut state = InputState::default();
+        state.apply(&[
+            InputEvent::Move { id: 2, x: 1.0, y: 1.0 },
+            InputEvent::Down { id: 2, x: 2.0, y: 2.0, paint: false },
+            InputEvent::Up { id: 2 },
+            InputEvent::Move { id: 2, x: 3.0, y: 3.0 },
+        ]);
+        assert!(state.contacts().is_empty());
+    }
+
+    #[test]
+    fn multi_touch_ids() {
+        let mut state = InputState::default();
+        state.apply(&[
+            InputEvent::Down { id: 1, x: 0.0, y: 0.0, paint: false },
+            InputEvent::Down { id: 2, x: 100.0, y: 0.0, paint: false },
+            InputEvent::Move { id: 2, x: 110.0, y: 5.0 },
+        ]);
+        let ids: Vec<i32> = state.contacts().iter().map(|contact| contact.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!((state.contacts()[0].x, state.contacts()[1].x), (0.0, 110.0));
+
+        // A repeated press replaces the contact with the same id instead of adding one
+        state.apply(&[InputEvent::Down { id: 1, x: 30.0, y: 0.0, paint: false }]);
+        assert_eq!(state.contacts().len(), 2);
+
+        state.apply(&[InputEvent::Up { id: 1 }]);
+        assert_eq!(state.contacts().len(), 1);
+        assert_eq!(state.contacts()[0].id, 2);
+    }
+
+    #[test]
+    fn velocity_smoothing() {
+        let mut state = InputState::default();
+        state.apply(&[InputEvent::Down { id: 1, x: 0.0, y: 0.0, paint: false }]);
+        state.update_velocities();
+        // Constant motion of 10 px per frame, the smoothed velocity approaches it geometrically
+        let mut expected = 0.0;
+        for frame in 1..=10 {
+            state.apply(&[InputEvent::Move { id: 1, x: 10.0 * frame as f32, y: 0.0 }]);
+            state.update_velocities();
+            expected += VELOCITY_SMOOTHING * (10.0 - expected);
+            let contact = state.contacts()[0];
+            assert!((contact.vx - expected).abs() < 1e-4);
+            assert_eq!(contact.vy, 0.0);
+        }
+        assert!((state.contacts()[0].vx - 10.0).abs() < 0.1);
+
+        // No motion, the velocity decays
+        state.update_velocities();
+        assert!(state.contacts()[0].vx < 10.0 * (1.0 - VELOCITY_SMOOTHING) + 0.1);
+    }
+}
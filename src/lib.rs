@@ -1,16 +1,28 @@
 mod utils;
-mod solver;
-
-use core::num;
-use std::char::MAX;
+pub mod solver;
+mod mpm;
+mod flip;
+pub mod force_field;
+mod input;
+pub mod colormap;
+pub mod raster;
+pub mod export;
+mod trail;
+pub mod surface;
+pub mod overlay;
+mod metaballs;
+pub mod obstacle;
+pub mod profiler;
+pub mod id_list;
 
 use wasm_bindgen::prelude::*;
 pub use wasm_bindgen_rayon::init_thread_pool;
-use web_time::Instant;
 
-use web_sys::{WebGl2RenderingContext, WebGlBuffer, WebGlProgram, WebGlShader, Window};
+use web_sys::{WebGl2RenderingContext, WebGlBuffer, WebGlProgram, WebGlShader, WebGlUniformLocation};
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+
+use colormap::{ColorMode, OBSTACLE_COLOR, WATER_COLOR, BACKGROUND_COLOR};
 
 
 #[wasm_bindgen]
@@ -21,7 +33,7 @@ extern "C" {
     pub fn log(s: &str);
 }
 
-static VERTEX_SHADER: &'static str = r#"
+static VERTEX_SHADER: &str = r#"
     varying highp vec3 vLighting;
     attribute vec3 aPosition;
     attribute vec3 aColor; 
@@ -37,15 +49,17 @@ static VERTEX_SHADER: &'static str = r#"
     }
 "#;
 
-static FRAGMENT_SHADER: &'static str = r#"
+static FRAGMENT_SHADER: &str = r#"
     precision mediump float;
     varying vec3 vColor; 
+    // Points are drawn as discs, triangles and lines as they are
+    uniform bool uDisc;
     void main() {
         lowp vec2 pos = gl_PointCoord - vec2(0.5, 0.5);
 		lowp float dist_squared = dot(pos, pos);
         lowp float alpha;
 
-        if (dist_squared < 0.25) {
+        if (!uDisc || dist_squared < 0.25) {
             alpha = 1.0;
         } else {
             alpha = 0.0;
@@ -57,268 +71,875 @@ static FRAGMENT_SHADER: &'static str = r#"
 
 pub struct Simulation {
     gl: WebGl2RenderingContext, 
-    buffers: BufferPair, 
+    vertex_buffer: VertexBuffer, 
+    // Interleaved vertices of the current frame, reused to avoid allocating every frame
+    vertices: RefCell<Vec<f32>>, 
+    // Particle colours and surface splat positions, likewise refreshed in place every frame
+    colors: RefCell<colormap::ParticleColors>, 
+    positions: RefCell<Vec<glam::Vec2>>, 
     state: solver::State, 
     mouse_info: MouseInfo, 
-    button_pressed: Rc<RefCell<bool>>, 
-    window_size: WindowSize, 
+    color_mode: ColorMode, 
+    color_map: colormap::ColorMap, 
+    render_mode: RenderMode, 
+    overlay: Overlay, 
+    canvas: web_sys::HtmlCanvasElement, 
+    shader: ShaderProgram, 
+    // None when the browser can't create the offscreen pass, metaballs then fall back to particles
+    metaballs: Option<metaballs::Metaballs>, 
+    canvas_size: CanvasSize, 
     scale: f32, 
+    resized: Rc<RefCell<bool>>, 
+    // Only held to be unregistered on drop
+    _resize_listener: EventListener, 
+    paused: bool, 
+    // One frame is advanced on the next `step` even when paused
+    step_requested: bool, 
+    scene: SceneParams, 
+    // Recorded only while the trails are drawn
+    trails: trail::Trails, 
+}
+
+// Settings that take effect on the next reset
+#[derive(Clone, Copy, Debug)]
+struct SceneParams {
+    num_particles: u32, 
+    // Material of the top part of the initial block and its fraction of the particles (the rest is liquid)
+    fill: (solver::Material, f32), 
+    field_height: f32, 
+    fill_level: f32, 
 }
 
+// Pointer events are queued by the listeners and applied at the start of each frame
 #[derive(Debug)]
 pub struct MouseInfo {
-    mouse_x: Rc<RefCell<f32>>, 
-    mouse_y: Rc<RefCell<f32>>,
-    is_dragging: Rc<RefCell<bool>>,
+    queue: Rc<RefCell<input::InputQueue>>, 
+    input: input::InputState, 
+    // Only held to be unregistered on drop
+    _listeners: Vec<EventListener>, 
 }
 
-struct BufferPair {
-    color_buffer: WebGlBuffer, 
-    position_buffer: WebGlBuffer, 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RenderMode {
+    // One disc per particle
+    Particles, 
+    // Filled isocontour of the splatted particles with an outline
+    Surface, 
+    // Gaussian blobs merged on the GPU
+    Metaballs, 
+    // Particles with streaks along their recent path, fading out
+    Trails, 
 }
 
-struct WindowSize {
-    width: f32, 
-    height: f32, 
+// Velocity field drawn on top of any render mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overlay {
+    None, 
+    // Arrow glyphs on a regular grid
+    Arrows, 
+    // Curves along the flow, seeded on a regular grid
+    Streamlines, 
 }
 
-const MAX_SPEED: f32 = 4.0;
+// Persistent GPU buffer that is overwritten in place and only reallocated when it has to grow
+struct VertexBuffer {
+    buffer: WebGlBuffer, 
+    // In floats
+    capacity: Cell<usize>, 
+}
+
+struct ShaderProgram {
+    program: WebGlProgram, 
+    resolution_location: WebGlUniformLocation, 
+    disc_location: WebGlUniformLocation, 
+}
 
-macro_rules! benchmark {
-    ($code:block) => {{
-        let start = Instant::now(); 
-        $code
-        start.elapsed().as_micros()
-    }};
+struct CanvasSize {
+    width: f32, 
+    height: f32, 
 }
 
+// x, y, point size, r, g, b
+const VERTEX_SIZE: usize = 6;
+// In frames
+const DEFAULT_TRAIL_LENGTH: usize = 12;
+const SURFACE_OUTLINE_COLOR: (f32, f32, f32) = (0.75, 0.9, 1.0);
+// Splat radius and iso level of the surface, relative to the particle spacing and the density at rest
+const SURFACE_RADIUS: f32 = 3.0 * solver::PARTICLE_SIZE;
+const SURFACE_ISO: f32 = 0.5;
+const OVERLAY_COLOR: (f32, f32, f32) = (0.95, 0.95, 0.95);
+// Grid spacing of the overlay in pixels
+const ARROW_SPACING: f32 = 20.0;
+const STREAMLINE_SPACING: f32 = 40.0;
+
 impl Simulation {
-    pub fn new(canvas: &web_sys::HtmlCanvasElement, num_particles: u32) -> Result<Simulation, JsValue> {
-        let window_size = get_window_size()?;
-        let scale = window_size.height / solver::State::height_from_num_particles(num_particles);
-        let (gl, buffers) = init_webgl(canvas, &window_size)?;
-        let aspect_ratio = window_size.width / window_size.height;
-        let state = solver::State::new(num_particles, aspect_ratio);
-        let button_pressed = init_button_info()?;
+    fn new(canvas: &web_sys::HtmlCanvasElement, scene: SceneParams) -> Result<Simulation, JsValue> {
+        let canvas_size = get_canvas_size(canvas);
+        let (gl, vertex_buffer, shader) = init_webgl(canvas, &canvas_size)?;
+        let vertices = RefCell::new(Vec::new());
+        let metaballs = match metaballs::Metaballs::new(&gl, &shader.program, canvas.width() as i32, canvas.height() as i32) {
+            Ok(metaballs) => Some(metaballs), 
+            Err(error) => {
+                log(&format!("metaballs unavailable: {:?}", error));
+                None
+            }
+        };
+        let state = solver::State::new(&scene.to_scene(&canvas_size));
+        let colors = RefCell::new(colormap::ParticleColors::new(&state, ColorMode::Dye, colormap::ColorMap::Rainbow));
+        let positions = RefCell::new(Vec::new());
+        let scale = canvas_size.height / state.field.height;
         let mouse_info = MouseInfo::new(canvas)?;
-        Ok(Simulation{ gl, buffers, state, mouse_info, button_pressed, window_size, scale })
+        let canvas = canvas.clone();
+        let (resized, resize_listener) = init_resize_info()?;
+        let color_mode = ColorMode::Quantity(solver::ParticleQuantity::Speed);
+        let color_map = colormap::ColorMap::Rainbow;
+        let render_mode = RenderMode::Particles;
+        let overlay = Overlay::None;
+        let paused = false;
+        let step_requested = false;
+        let trails = trail::Trails::new(DEFAULT_TRAIL_LENGTH);
+        Ok(Simulation{ gl, vertex_buffer, vertices, colors, positions, state, mouse_info, color_mode, color_map, render_mode, overlay, canvas, shader, metaballs, canvas_size, scale, resized, _resize_listener: resize_listener, paused, step_requested, scene, trails })
     }
 
     pub fn draw(&self) {
-        self.gl.clear_color(0.4, 0.4, 0.4, 1.0);
+        self.gl.clear_color(BACKGROUND_COLOR.0, BACKGROUND_COLOR.1, BACKGROUND_COLOR.2, 1.0);
         self.gl.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT);
 
         // アルファ値の設定のために必要らしい？（TODO : 調べる）
         self.gl.enable(WebGl2RenderingContext::BLEND);
         self.gl.blend_func(WebGl2RenderingContext::SRC_ALPHA, WebGl2RenderingContext::ONE_MINUS_SRC_ALPHA);
 
-        let positions = self.generate_positions();
-        self.gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&self.buffers.position_buffer));
-        unsafe {
-            self.gl.buffer_data_with_array_buffer_view(
-                WebGl2RenderingContext::ARRAY_BUFFER, 
-                &js_sys::Float32Array::view(&positions), 
-                WebGl2RenderingContext::DYNAMIC_DRAW
-            );
+        let mut vertices = self.vertices.borrow_mut();
+        vertices.clear();
+        let mut colors = self.colors.borrow_mut();
+        if self.render_mode != RenderMode::Surface {
+            colors.update(&self.state, self.color_mode, self.color_map);
+        }
+        match (self.render_mode, &self.metaballs) {
+            (RenderMode::Metaballs, Some(metaballs)) => {
+                self.push_particles(&mut vertices, &colors);
+                let num_particles = vertices.len() / VERTEX_SIZE;
+                self.push_obstacles(&mut vertices);
+                self.upload(&vertices, 0);
+                metaballs.draw(&self.gl, &self.shader.program, num_particles as i32);
+                self.draw_range(WebGl2RenderingContext::POINTS, num_particles, vertices.len() / VERTEX_SIZE);
+            }
+            (RenderMode::Trails, _) => {
+                // Streaks below the particles, fading into the background with age
+                // Particles added or removed since the last step have no history yet
+                if self.trails.particle_count() == Some(self.state.particles.len()) {
+                    vertices.reserve(self.trails.num_segments() * 2 * VERTEX_SIZE);
+                    self.trails.for_each_segment(|i, from, to, age|{
+                        if let Some(particle) = self.state.particles.get(i) {
+                            let color = fade(colors.color(i, particle), BACKGROUND_COLOR, age);
+                            self.push_points(&mut vertices, &[ from, to ], 0.0, color);
+                        }
+                    });
+                }
+                let num_trail_vertices = vertices.len() / VERTEX_SIZE;
+                self.push_particles(&mut vertices, &colors);
+                self.push_obstacles(&mut vertices);
+                self.upload(&vertices, 0);
+
+                self.gl.uniform1i(Some(&self.shader.disc_location), 0);
+                self.draw_range(WebGl2RenderingContext::LINES, 0, num_trail_vertices);
+                self.gl.uniform1i(Some(&self.shader.disc_location), 1);
+                self.draw_range(WebGl2RenderingContext::POINTS, num_trail_vertices, vertices.len() / VERTEX_SIZE);
+            }
+            (RenderMode::Particles | RenderMode::Metaballs, _) => {
+                // Obstacles are drawn as points after the particles
+                self.push_particles(&mut vertices, &colors);
+                self.push_obstacles(&mut vertices);
+                self.upload(&vertices, 0);
+                self.draw_range(WebGl2RenderingContext::POINTS, 0, vertices.len() / VERTEX_SIZE);
+            }
+            (RenderMode::Surface, _) => {
+                // Edges are smoothed by the multisampling of the default framebuffer
+                let contour = self.generate_surface();
+                self.push_points(&mut vertices, &contour.triangles, 0.0, WATER_COLOR);
+                self.push_points(&mut vertices, &contour.segments, 0.0, SURFACE_OUTLINE_COLOR);
+                self.push_obstacles(&mut vertices);
+                self.upload(&vertices, 0);
+
+                let outline_start = contour.triangles.len();
+                let obstacle_start = outline_start + contour.segments.len();
+                self.gl.uniform1i(Some(&self.shader.disc_location), 0);
+                self.draw_range(WebGl2RenderingContext::TRIANGLES, 0, outline_start);
+                self.draw_range(WebGl2RenderingContext::LINES, outline_start, obstacle_start);
+                self.gl.uniform1i(Some(&self.shader.disc_location), 1);
+                self.draw_range(WebGl2RenderingContext::POINTS, obstacle_start, vertices.len() / VERTEX_SIZE);
+            }
+        }
+
+        let overlay_lines = match self.overlay {
+            Overlay::None => return, 
+            Overlay::Arrows => overlay::arrows(&self.state, ARROW_SPACING / self.scale), 
+            Overlay::Streamlines => overlay::streamlines(&self.state, STREAMLINE_SPACING / self.scale), 
+        };
+        let overlay_start = vertices.len() / VERTEX_SIZE;
+        self.push_points(&mut vertices, &overlay_lines, 0.0, OVERLAY_COLOR);
+        self.upload(&vertices, overlay_start);
+        self.gl.uniform1i(Some(&self.shader.disc_location), 0);
+        self.draw_range(WebGl2RenderingContext::LINES, overlay_start, vertices.len() / VERTEX_SIZE);
+        self.gl.uniform1i(Some(&self.shader.disc_location), 1);
+    }
+
+    // Vertices `start..end` of the last upload
+    fn draw_range(&self, mode: u32, start: usize, end: usize) {
+        if end > start {
+            self.gl.draw_arrays(mode, start as i32, (end - start) as i32);
+        }
+    }
+
+    // Uploads the vertices from `start` on, the ones before are already in the buffer
+    fn upload(&self, vertices: &[f32], start: usize) {
+        self.gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&self.vertex_buffer.buffer));
+        let mut offset = start * VERTEX_SIZE;
+        if vertices.len() > self.vertex_buffer.capacity.get() {
+            // Room to grow, so that adding particles doesn't reallocate every frame
+            let capacity = vertices.len() * 2;
+            self.gl.buffer_data_with_i32(WebGl2RenderingContext::ARRAY_BUFFER, (capacity * 4) as i32, WebGl2RenderingContext::DYNAMIC_DRAW);
+            self.vertex_buffer.capacity.set(capacity);
+            // Reallocating drops the old contents
+            offset = 0;
         }
-        self.gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&self.buffers.color_buffer));
-        let colors = self.generate_colors();
         unsafe {
-            self.gl.buffer_data_with_array_buffer_view(
+            self.gl.buffer_sub_data_with_i32_and_array_buffer_view(
                 WebGl2RenderingContext::ARRAY_BUFFER, 
-                &js_sys::Float32Array::view(&colors), 
-                WebGl2RenderingContext::DYNAMIC_DRAW
+                (offset * 4) as i32, 
+                &js_sys::Float32Array::view(&vertices[offset..])
             );
         }
-        self.gl.draw_arrays(WebGl2RenderingContext::POINTS, 0, self.state.particles.len() as i32);
     }
 
-    fn reset(&mut self, num_particles: u32) {
-        self.state.init_particles(num_particles, self.window_size.width / self.window_size.height);
-        self.scale = self.window_size.height / solver::State::height_from_num_particles(num_particles);
-        *self.button_pressed.borrow_mut() = false;
+    fn generate_surface(&self) -> surface::Contour {
+        let mut positions = self.positions.borrow_mut();
+        positions.clear();
+        positions.extend(self.state.particles.iter().map(|particle| particle.position));
+        let field = &self.state.field;
+        let grid = surface::DensityGrid::splat(&positions, field.width, field.height, solver::PARTICLE_SIZE, SURFACE_RADIUS, solver::PARTICLE_SIZE);
+        surface::marching_squares(&grid, SURFACE_ISO)
     }
 
-    fn generate_positions(&self) -> Vec<f32> {
-        self.state.particles.iter().flat_map(|particle|{
-            let x = particle.position.x * self.scale;
-            let y = particle.position.y * self.scale;
-            let r = solver::PARTICLE_SIZE * self.scale;
-            vec![ x, y, r ]
-        }).collect()
+    fn warn_unsupported_materials(&self) {
+        let solver_kind = self.state.solver_kind;
+        if let Some(particle) = self.state.particles.iter().find(|particle| !solver_kind.supports(particle.material)) {
+            log(&format!("{:?} is not supported by the {:?} solver and runs as liquid", particle.material, solver_kind));
+        }
     }
 
-    fn generate_colors(&self) -> Vec<f32> {
-        self.state.particles.iter().flat_map(|particle|{
-            let (r, g, b, a) = get_color_by_speed(particle.velocity.length());
-            vec![ r, g, b ]
-        }).collect()
+    fn reset(&mut self) {
+        self.state.init_particles(&self.scene.to_scene(&self.canvas_size));
+        self.scale = self.canvas_size.height / self.state.field.height;
+        self.trails.clear();
+    }
+
+    // Follows the displayed size of the canvas. The scale stays the same, so the domain grows or shrinks
+    // at the right and top and the particles keep their place on the screen.
+    fn resize(&mut self) {
+        self.canvas_size = get_canvas_size(&self.canvas);
+        self.canvas.set_width(self.canvas_size.width as u32);
+        self.canvas.set_height(self.canvas_size.height as u32);
+        self.gl.viewport(0, 0, self.canvas.width() as i32, self.canvas.height() as i32);
+        self.gl.uniform2f(Some(&self.shader.resolution_location), self.canvas.width() as f32, self.canvas.height() as f32);
+        if let Some(metaballs) = &self.metaballs {
+            if let Err(error) = metaballs.resize(&self.gl, &self.shader.program, self.canvas.width() as i32, self.canvas.height() as i32) {
+                log(&format!("cannot resize metaballs, falling back to particles: {:?}", error));
+                self.metaballs = None;
+            }
+        }
+        // A hidden canvas has no size, the field keeps its own until the canvas shows up again
+        if self.canvas_size.width > 0.0 && self.canvas_size.height > 0.0 {
+            if self.scale <= 0.0 {
+                self.scale = self.canvas_size.height / self.state.field.height;
+            }
+            self.state.resize_field(self.canvas_size.width / self.scale, self.canvas_size.height / self.scale);
+        }
+        *self.resized.borrow_mut() = false;
+    }
+
+    fn push_particles(&self, vertices: &mut Vec<f32>, colors: &colormap::ParticleColors) {
+        vertices.reserve(self.state.particles.len() * VERTEX_SIZE);
+        for (i, particle) in self.state.particles.iter().enumerate() {
+            let (r, g, b) = colors.color(i, particle);
+            let position = particle.position * self.scale;
+            vertices.extend_from_slice(&[ position.x, position.y, particle.size * self.scale, r, g, b ]);
+        }
+    }
+
+    // Field coordinates to canvas pixels
+    fn push_points(&self, vertices: &mut Vec<f32>, points: &[glam::Vec2], size: f32, color: (f32, f32, f32)) {
+        vertices.reserve(points.len() * VERTEX_SIZE);
+        for point in points {
+            vertices.extend_from_slice(&[ point.x * self.scale, point.y * self.scale, size * self.scale, color.0, color.1, color.2 ]);
+        }
+    }
+
+    fn push_obstacles(&self, vertices: &mut Vec<f32>) {
+        for obstacle in &self.state.obstacles {
+            self.push_points(vertices, &obstacle.sample_points(solver::PARTICLE_SIZE), solver::PARTICLE_SIZE, OBSTACLE_COLOR);
+        }
+    }
+
+    fn canvas_to_field(&self, x: f32, y: f32) -> glam::Vec2 {
+        glam::Vec2::new(x / self.scale, self.state.field.height - y / self.scale)
     }
 
     pub fn step(&mut self) {
-        let mouse_vec = glam::Vec2::new(*self.mouse_info.mouse_x.borrow() / self.scale, self.state.field.height - *self.mouse_info.mouse_y.borrow() / self.scale);
-        let t = benchmark!({self.state.update(mouse_vec, *self.mouse_info.is_dragging.borrow())});
-        let s = format!("{} ms", t / 1000);
-        log(&s);
+        if *self.resized.borrow() {
+            self.resize();
+        }
+        let events = self.mouse_info.queue.borrow_mut().drain();
+        self.mouse_info.input.apply(&events);
+        self.mouse_info.input.update_velocities();
+
+        let mut pointers = Vec::new();
+        for contact in self.mouse_info.input.contacts() {
+            let position = self.canvas_to_field(contact.x, contact.y);
+            if contact.paint {
+                let radius = self.state.field.height / 10.0;
+                self.state.paint_dye(position, radius, 1.0);
+                continue;
+            }
+            // Canvas y points down
+            let velocity = glam::Vec2::new(contact.vx, -contact.vy) / self.scale / self.state.frame_time();
+            pointers.push(solver::Pointer { position, velocity });
+        }
+
+        if self.paused && !self.step_requested {
+            return;
+        }
+        if std::mem::take(&mut self.step_requested) {
+            self.state.single_step(&pointers);
+        } else {
+            self.state.update(&pointers);
+        }
+        if self.render_mode == RenderMode::Trails {
+            self.trails.record(&self.state.particles);
+        }
+    }
+}
+
+fn parse_solver(name: &str) -> Option<solver::SolverKind> {
+    match name {
+        "sph" => Some(solver::SolverKind::Sph), 
+        "mpm" => Some(solver::SolverKind::Mpm), 
+        "flip" => Some(solver::SolverKind::Flip), 
+        _ => None, 
+    }
+}
+
+// Material of the top part of the initial block and its fraction, the rest is liquid
+fn parse_fill(name: &str) -> Option<(solver::Material, f32)> {
+    match name {
+        "water" => Some((solver::Material::Liquid, 0.0)), 
+        "sand" => Some((solver::Material::Sand, 1.0)), 
+        "mixed" => Some((solver::Material::Sand, 0.5)), 
+        "jelly" => Some((solver::Material::Jelly, 0.4)), 
+        "snow" => Some((solver::Material::Snow, 1.0)), 
+        _ => None, 
+    }
+}
+
+impl SceneParams {
+    // The field has the aspect ratio of the canvas
+    fn to_scene(self, canvas_size: &CanvasSize) -> solver::Scene {
+        // A canvas without height (not laid out yet) gets a square field instead of an infinite one
+        let aspect = canvas_size.width / canvas_size.height;
+        let field_width = self.field_height * if aspect.is_finite() && aspect > 0.0 { aspect } else { 1.0 };
+        solver::Scene {
+            num_particles: self.num_particles, 
+            field_width, 
+            field_height: self.field_height, 
+            fill_level: self.fill_level, 
+            top_material: self.fill.0, 
+            top_fraction: self.fill.1, 
+        }
+    }
+}
+
+impl Default for SceneParams {
+    fn default() -> Self {
+        SceneParams {
+            num_particles: solver::DEFAULT_PARTICLE_COUNT, 
+            fill: (solver::Material::Liquid, 0.0), 
+            field_height: solver::DEFAULT_FIELD_HEIGHT, 
+            fill_level: solver::DEFAULT_FILL_LEVEL, 
+        }
     }
 }
 
 impl MouseInfo {
     pub fn new(canvas: &web_sys::HtmlCanvasElement) -> Result<MouseInfo, JsValue> {
-        let mouse_x = Rc::new(RefCell::new(0.0));
-        let mouse_y = Rc::new(RefCell::new(0.0));
-        let is_dragging = Rc::new(RefCell::new(false));
+        let queue = Rc::new(RefCell::new(input::InputQueue::default()));
+        let input = input::InputState::default();
+        let mut listeners = Vec::new();
 
         {
-            let mouse_x = mouse_x.clone();
-            let mouse_y = mouse_y.clone();
-            add_event_listener(&canvas, "mousemove", move |event| {
-                let mouse_event = event.dyn_into::<web_sys::MouseEvent>().unwrap();
-                *mouse_x.borrow_mut() = mouse_event.offset_x() as f32;
-                *mouse_y.borrow_mut() = mouse_event.offset_y() as f32;
-            })?;
+            let queue = queue.clone();
+            let target = canvas.clone();
+            listeners.push(add_event_listener(canvas, "pointerdown", move |event| {
+                let pointer_event = event.dyn_into::<web_sys::PointerEvent>().unwrap();
+                // Keep receiving the moves of this contact even when it leaves the canvas
+                let _ = target.set_pointer_capture(pointer_event.pointer_id());
+                queue.borrow_mut().push(input::InputEvent::Down {
+                    id: pointer_event.pointer_id(), 
+                    x: pointer_event.offset_x() as f32, 
+                    y: pointer_event.offset_y() as f32, 
+                    // Shift + drag paints dye instead of applying the tool
+                    paint: pointer_event.shift_key(), 
+                });
+            })?);
         }
 
         {
-            let is_dragging = is_dragging.clone();
-            add_event_listener(&canvas, "mouseleave", move|event|{
-                *is_dragging.borrow_mut() = false;
-            })?;
+            let queue = queue.clone();
+            listeners.push(add_event_listener(canvas, "pointermove", move |event| {
+                let pointer_event = event.dyn_into::<web_sys::PointerEvent>().unwrap();
+                queue.borrow_mut().push(input::InputEvent::Move {
+                    id: pointer_event.pointer_id(), 
+                    x: pointer_event.offset_x() as f32, 
+                    y: pointer_event.offset_y() as f32, 
+                });
+            })?);
         }
 
-        {
-            let is_dragging = is_dragging.clone();
-            add_event_listener(&canvas, "mouseup", move|event|{
-                *is_dragging.borrow_mut() = false;
-            })?;
+        for event_name in ["pointerup", "pointercancel"] {
+            let queue = queue.clone();
+            listeners.push(add_event_listener(canvas, event_name, move |event| {
+                let pointer_event = event.dyn_into::<web_sys::PointerEvent>().unwrap();
+                queue.borrow_mut().push(input::InputEvent::Up { id: pointer_event.pointer_id() });
+            })?);
         }
 
-        {
-            let is_dragging = is_dragging.clone();
-            add_event_listener(&canvas, "mousedown", move|event|{
-                *is_dragging.borrow_mut() = true;
-            })?;
+        Ok(Self { queue, input, _listeners: listeners })
+    }
+}
+
+fn init_resize_info() -> Result<(Rc<RefCell<bool>>, EventListener), JsValue> {
+    let resized = Rc::new(RefCell::new(false));
+    let listener = {
+        let resized = resized.clone();
+        add_event_listener(&window(), "resize", move |_event| {
+            *resized.borrow_mut() = true;
+        })?
+    };
+    Ok((resized, listener))
+}
+
+// A simulation drawing into its own canvas. The embedding page drives it by calling `step` and `render`
+// every frame, several of them can run side by side.
+#[wasm_bindgen(js_name = Simulation)]
+pub struct SimulationHandle {
+    sim: Rc<RefCell<Simulation>>, 
+}
+
+#[wasm_bindgen(js_class = Simulation)]
+impl SimulationHandle {
+    // `options` is `{ count, params }`, both optional. `params` holds initial values for `set_param`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(canvas: web_sys::HtmlCanvasElement, options: JsValue) -> Result<SimulationHandle, JsValue> {
+        utils::set_panic_hook();
+        let mut scene = SceneParams::default();
+        let mut params = Vec::new();
+        if options.is_object() {
+            let count = js_sys::Reflect::get(&options, &JsValue::from("count"))?;
+            if !count.is_undefined() {
+                let count = count.as_f64().filter(|count| count.is_finite()).ok_or(JsValue::from("count must be a finite number"))?;
+                scene.num_particles = count.clamp(0.0, solver::MAX_PARTICLES as f64) as u32;
+            }
+            let object = js_sys::Reflect::get(&options, &JsValue::from("params"))?;
+            if object.is_object() {
+                for entry in js_sys::Object::entries(&object.dyn_into::<js_sys::Object>()?).iter() {
+                    let entry = entry.dyn_into::<js_sys::Array>()?;
+                    params.push((entry.get(0).as_string().unwrap_or_default(), entry.get(1)));
+                }
+            }
         }
 
-        Ok(Self { mouse_x, mouse_y, is_dragging })
+        let handle = SimulationHandle { sim: Rc::new(RefCell::new(Simulation::new(&canvas, scene)?)) };
+        for (name, value) in params {
+            handle.set_param(&name, value)?;
+        }
+        // Scene params given in the options apply from the start
+        handle.reset();
+        Ok(handle)
     }
 
+    // Advances the simulation by one frame, unless paused.
+    // Returns nothing, call `diagnostics` afterwards for the energies and density errors of the new state.
+    pub fn step(&self) {
+        self.sim.borrow_mut().step();
+    }
 
-}
+    pub fn render(&self) {
+        self.sim.borrow().draw();
+    }
 
-fn init_button_info() -> Result<Rc<RefCell<bool>>, JsValue> {
-    let button_pressed = Rc::new(RefCell::new(false));
-    let document = window().document().unwrap();
-    let button_element = document.get_element_by_id("reset-button").ok_or(JsValue::from("reset-button doesn't exist"))?;
-    {
-        let button_pressed_clone = button_pressed.clone();
-        add_event_listener(&button_element, "click", move |event| {
-            *button_pressed_clone.borrow_mut() = true;
-        });
-    }
-    Ok(button_pressed)
-}
+    // Starts over with the current scene params
+    pub fn reset(&self) {
+        let mut sim = self.sim.borrow_mut();
+        sim.reset();
+        sim.warn_unsupported_materials();
+    }
 
-#[wasm_bindgen]
-pub fn start() -> Result<(), JsValue> {
-    let canvas = get_canvas_element_by_id("canvas")?;
-    let num_particles = get_particle_count().unwrap();
-    let mut sim = Simulation::new(&canvas, num_particles)?;
-
-    start_animation(move||{
-        if *sim.button_pressed.borrow() {
-            let num_particles = get_particle_count().unwrap();
-            sim.reset(num_particles);
+    // Whether the solver ("sph", "mpm", "flip") has a model for every material of the scene material
+    // ("water", "sand", ...). Unsupported materials run as liquid.
+    pub fn supports_material(solver: &str, material: &str) -> Result<bool, JsValue> {
+        let solver_kind = parse_solver(solver).ok_or_else(|| JsValue::from(format!("unknown solver: {}", solver)))?;
+        let (top_material, _) = parse_fill(material).ok_or_else(|| JsValue::from(format!("unknown material: {}", material)))?;
+        Ok(solver_kind.supports(top_material))
+    }
+
+    pub fn pause(&self) {
+        self.sim.borrow_mut().paused = true;
+    }
+
+    pub fn resume(&self) {
+        self.sim.borrow_mut().paused = false;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn paused(&self) -> bool {
+        self.sim.borrow().paused
+    }
+
+    // Advances one frame on the next `step`, for inspecting a paused simulation.
+    // In slow motion, where a frame may have no substep, it advances a single substep.
+    pub fn single_step(&self) {
+        self.sim.borrow_mut().step_requested = true;
+    }
+
+    // Simulated time per frame relative to normal speed, clamped to [1/32, 4]
+    #[wasm_bindgen(getter)]
+    pub fn time_scale(&self) -> f32 {
+        self.sim.borrow().state.time_scale()
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_time_scale(&self, time_scale: f32) {
+        self.sim.borrow_mut().state.set_time_scale(time_scale);
+    }
+
+    pub fn particle_count(&self) -> usize {
+        self.sim.borrow().state.particles.len()
+    }
+
+    // Diagnostics of the current state as a plain object, with the fields of `solver::Diagnostics`
+    // and the momentum split into momentum_x and momentum_y. Computed on every call, the simulation
+    // doesn't spend time on them when nobody asks.
+    pub fn diagnostics(&self) -> Result<JsValue, JsValue> {
+        let d = self.sim.borrow().state.diagnostics();
+        let object = js_sys::Object::new();
+        for (key, value) in [
+            ("time", d.time), 
+            ("particle_count", d.particle_count as f32), 
+            ("kinetic_energy", d.kinetic_energy), 
+            ("potential_energy", d.potential_energy), 
+            ("momentum_x", d.momentum.x), 
+            ("momentum_y", d.momentum.y), 
+            ("mean_density_error", d.mean_density_error), 
+            ("max_density_error", d.max_density_error), 
+            ("max_speed", d.max_speed), 
+            ("mean_neighbors", d.mean_neighbors), 
+            ("min_neighbors", d.min_neighbors as f32), 
+            ("max_neighbors", d.max_neighbors as f32), 
+        ] {
+            js_sys::Reflect::set(&object, &JsValue::from(key), &JsValue::from(value))?;
         }
-        sim.step();
-        sim.draw();
-    });
-        
-    Ok(())
-}
+        Ok(object.into())
+    }
 
-fn window() -> web_sys::Window {
-    web_sys::window().expect("no global `window` exists")
-}
+    // Solver time per frame over the recent frames, as [{ phase, mean, p50, p95, max }, ...] in ms.
+    // The last entry is the whole update.
+    pub fn profile(&self) -> Result<JsValue, JsValue> {
+        let sim = self.sim.borrow();
+        let array = js_sys::Array::new();
+        for phase in profiler::Phase::ALL {
+            let stats = sim.state.profiler().stats(phase);
+            let object = js_sys::Object::new();
+            js_sys::Reflect::set(&object, &JsValue::from("phase"), &JsValue::from(phase.name()))?;
+            for (key, value) in [("mean", stats.mean), ("p50", stats.p50), ("p95", stats.p95), ("max", stats.max)] {
+                js_sys::Reflect::set(&object, &JsValue::from(key), &JsValue::from(value))?;
+            }
+            array.push(&object);
+        }
+        Ok(array.into())
+    }
 
-fn get_window_size() -> Result<WindowSize, JsValue> {
-    let window = window();
-    let width = window.inner_width()?.as_f64().unwrap() as f32;
-    let height = window.inner_height()?.as_f64().unwrap() as f32;
-    Ok(WindowSize { width, height })
-}
+    // Interpolated [density, vx, vy, pressure] at a point in canvas pixels, velocity in m/s with y up
+    pub fn sample_point(&self, x: f32, y: f32) -> Vec<f32> {
+        let sim = self.sim.borrow();
+        let sample = sim.state.sample_point(sim.canvas_to_field(x, y));
+        vec![sample.density, sample.velocity.x, sample.velocity.y, sample.pressure]
+    }
 
-fn get_particle_count() -> Result<u32, JsValue> {
-    let document = window().document().unwrap();
-    let element = document.get_element_by_id("slider-value")
-        .ok_or(JsValue::from("slider-value doesn't exist."))?;
-    let text_content = element.text_content().unwrap();
-    Ok(text_content.trim().parse::<u32>().unwrap())
-}
+    // "density", "velocity" (vx, vy interleaved) or "pressure" at the centres of an nx * ny grid over the field,
+    // row-major with the bottom row first
+    pub fn sample_grid(&self, nx: usize, ny: usize, quantity: &str) -> Result<Vec<f32>, JsValue> {
+        let grid = self.sim.borrow().state.sample_grid(nx, ny);
+        match quantity {
+            "density" => Ok(grid.density), 
+            "velocity" => Ok(grid.velocity), 
+            "pressure" => Ok(grid.pressure), 
+            other => Err(JsValue::from(format!("unknown quantity: {}", other))), 
+        }
+    }
 
-fn request_animation_frame(f: &Closure<dyn FnMut()>) {
-    window()
-        .request_animation_frame(f.as_ref().unchecked_ref())
-        .expect("should register `requestAnimationFrame` OK");
-}
+    // Scene params, applied on the next `reset`:
+    //   "count", "material" ("water", "sand", "mixed", "jelly", "snow"), "field_height" (m), "fill_level"
+    // Applied immediately:
+    //   "solver" ("sph", "mpm", "flip"), "color_mode" ("speed", "density", "pressure", "near_pressure", "force",
+    //   "vorticity", "dye"), "color_map" ("rainbow", "viridis", "magma", "diverging"), "render_mode" ("particles", "surface", "metaballs", "trails"),
+    //   "flip_ratio", "dye_diffusion", "gravity_x", "gravity_y", "tool", "tool_radius", "tool_strength", "time_scale",
+    //   "trail_length" (frames), "overlay" ("none", "arrows", "streamlines")
+    pub fn set_param(&self, name: &str, value: JsValue) -> Result<(), JsValue> {
+        let number = || value.as_f64().map(|x| x as f32).filter(|x| x.is_finite())
+            .ok_or(JsValue::from(format!("{} must be a finite number", name)));
+        let string = || value.as_string().ok_or(JsValue::from(format!("{} must be a string", name)));
+        let unknown = |value: String| JsValue::from(format!("unknown {}: {}", name, value));
+
+        let mut sim = self.sim.borrow_mut();
+        match name {
+            "count" => sim.scene.num_particles = number()?.clamp(0.0, solver::MAX_PARTICLES as f32) as u32, 
+            "material" => {
+                let material = string()?;
+                sim.scene.fill = parse_fill(&material).ok_or_else(|| unknown(material))?;
+            }
+            "field_height" => sim.scene.field_height = number()?.clamp(solver::PARTICLE_SIZE * 10.0, solver::MAX_FIELD_SIZE), 
+            "fill_level" => sim.scene.fill_level = number()?, 
+            "solver" => {
+                let solver_kind = string()?;
+                sim.state.solver_kind = parse_solver(&solver_kind).ok_or_else(|| unknown(solver_kind))?;
+                sim.warn_unsupported_materials();
+            }
+            "color_mode" => {
+                sim.color_mode = match string()?.as_str() {
+                    "speed" => ColorMode::Quantity(solver::ParticleQuantity::Speed), 
+                    "density" => ColorMode::Quantity(solver::ParticleQuantity::Density), 
+                    "pressure" => ColorMode::Quantity(solver::ParticleQuantity::Pressure), 
+                    "near_pressure" => ColorMode::Quantity(solver::ParticleQuantity::NearPressure), 
+                    "force" => ColorMode::Quantity(solver::ParticleQuantity::Force), 
+                    "vorticity" => ColorMode::Quantity(solver::ParticleQuantity::Vorticity), 
+                    "dye" => ColorMode::Dye, 
+                    other => return Err(unknown(other.to_string())), 
+                };
+            }
+            "render_mode" => {
+                let render_mode = match string()?.as_str() {
+                    "particles" => RenderMode::Particles, 
+                    "surface" => RenderMode::Surface, 
+                    "metaballs" => RenderMode::Metaballs, 
+                    "trails" => RenderMode::Trails, 
+                    other => return Err(unknown(other.to_string())), 
+                };
+                // Trails aren't recorded in the other modes, an old history would jump to the current positions
+                if render_mode == RenderMode::Trails && sim.render_mode != RenderMode::Trails {
+                    sim.trails.clear();
+                }
+                sim.render_mode = render_mode;
+            }
+            "overlay" => {
+                sim.overlay = match string()?.as_str() {
+                    "none" => Overlay::None, 
+                    "arrows" => Overlay::Arrows, 
+                    "streamlines" => Overlay::Streamlines, 
+                    other => return Err(unknown(other.to_string())), 
+                };
+            }
+            "color_map" => {
+                sim.color_map = match string()?.as_str() {
+                    "rainbow" => colormap::ColorMap::Rainbow, 
+                    "viridis" => colormap::ColorMap::Viridis, 
+                    "magma" => colormap::ColorMap::Magma, 
+                    "diverging" => colormap::ColorMap::Diverging, 
+                    other => return Err(unknown(other.to_string())), 
+                };
+            }
+            "flip_ratio" => sim.state.set_flip_ratio(number()?), 
+            "dye_diffusion" => sim.state.dye_diffusion = number()?.max(0.0), 
+            "gravity_x" => sim.state.gravity.x = number()?, 
+            "gravity_y" => sim.state.gravity.y = number()?, 
+            "tool" => {
+                drop(sim);
+                return self.set_tool(&string()?);
+            }
+            "tool_radius" => sim.state.tool.radius = number()?.max(0.0), 
+            "tool_strength" => sim.state.tool.strength = number()?.max(0.0), 
+            "time_scale" => sim.state.set_time_scale(number()?), 
+            "trail_length" => sim.trails.length = number()?.max(1.0) as usize, 
+            _ => return Err(JsValue::from(format!("unknown param: {}", name))), 
+        }
+        Ok(())
+    }
+
+    // Gravity in m/s^2, +y is up
+    pub fn set_gravity(&self, x: f32, y: f32) {
+        self.sim.borrow_mut().state.gravity = glam::Vec2::new(x, y);
+    }
 
-fn start_animation<T>(mut handler: T)
-where T: 'static + FnMut()
-{ 
-    let f = Rc::new(RefCell::new(None));
-    let g = f.clone();
-    *g.borrow_mut() = Some(Closure::wrap(Box::new(move || {
-        handler();
-        request_animation_frame(f.borrow().as_ref().unwrap());
-    }) as Box<dyn FnMut()>));
-
-    request_animation_frame(g.borrow().as_ref().unwrap());
+    pub fn reset_gravity(&self) {
+        self.sim.borrow_mut().state.gravity = solver::GRV;
+    }
+
+    // Position and radius in canvas pixels, strength in m/s^2 (negative repels). Returns the id of the attractor.
+    pub fn add_attractor(&self, x: f32, y: f32, strength: f32, radius: f32) -> u32 {
+        let mut sim = self.sim.borrow_mut();
+        let position = sim.canvas_to_field(x, y);
+        let radius = radius / sim.scale;
+        sim.state.attractors.push(solver::Attractor { position, strength, radius })
+    }
+
+    pub fn remove_attractor(&self, id: u32) {
+        self.sim.borrow_mut().state.attractors.remove(id);
+    }
+
+    pub fn clear_attractors(&self) {
+        self.sim.borrow_mut().state.attractors.clear();
+    }
+
+    // One of "attract", "repel", "swirl", "drag", "add" and "delete"
+    pub fn set_tool(&self, mode: &str) -> Result<(), JsValue> {
+        let mode = match mode {
+            "attract" => solver::ToolMode::Attract, 
+            "repel" => solver::ToolMode::Repel, 
+            "swirl" => solver::ToolMode::Swirl, 
+            "drag" => solver::ToolMode::Drag, 
+            "add" => solver::ToolMode::Add, 
+            "delete" => solver::ToolMode::Delete, 
+            _ => return Err(JsValue::from(format!("unknown tool: {}", mode))), 
+        };
+        self.sim.borrow_mut().state.tool.mode = mode;
+        Ok(())
+    }
+
+    // Radius as a fraction of the field height
+    #[wasm_bindgen(getter)]
+    pub fn tool_radius(&self) -> f32 {
+        self.sim.borrow().state.tool.radius
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_tool_radius(&self, radius: f32) {
+        self.sim.borrow_mut().state.tool.radius = radius.max(0.0);
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn tool_strength(&self) -> f32 {
+        self.sim.borrow().state.tool.strength
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_tool_strength(&self, strength: f32) {
+        self.sim.borrow_mut().state.tool.strength = strength.max(0.0);
+    }
+
+    // Obstacles. Positions and sizes are in canvas pixels, angles in radians (counter-clockwise).
+    // Each returns the id of the obstacle.
+    pub fn add_box_obstacle(&self, x: f32, y: f32, width: f32, height: f32, angle: f32) -> u32 {
+        let mut sim = self.sim.borrow_mut();
+        let position = sim.canvas_to_field(x, y);
+        let half_extents = glam::Vec2::new(width, height) * 0.5 / sim.scale;
+        sim.state.obstacles.push(obstacle::Obstacle::new(obstacle::Shape::Box { half_extents }, position, angle))
+    }
+
+    pub fn add_circle_obstacle(&self, x: f32, y: f32, radius: f32) -> u32 {
+        let mut sim = self.sim.borrow_mut();
+        let position = sim.canvas_to_field(x, y);
+        let radius = radius / sim.scale;
+        sim.state.obstacles.push(obstacle::Obstacle::new(obstacle::Shape::Circle { radius }, position, 0.0))
+    }
+
+    // Moves the obstacle to the transform over the next frame, call it every frame to drive it from JS
+    pub fn set_obstacle_transform(&self, id: u32, x: f32, y: f32, angle: f32) {
+        let mut sim = self.sim.borrow_mut();
+        let position = sim.canvas_to_field(x, y);
+        let frame_time = sim.state.frame_time();
+        if let Some(obstacle) = sim.state.obstacles.get_mut(id) {
+            obstacle.set_transform(position, angle, frame_time);
+        }
+    }
+
+    // Flat [time, x, y, angle, ...] with times in seconds of simulated time, looped
+    pub fn set_obstacle_keyframes(&self, id: u32, keyframes: Vec<f32>) {
+        let mut sim = self.sim.borrow_mut();
+        let keyframes: Vec<obstacle::Keyframe> = keyframes.chunks_exact(4).map(|k|{
+            obstacle::Keyframe { time: k[0], position: sim.canvas_to_field(k[1], k[2]), angle: k[3] }
+        }).collect();
+        if let Some(obstacle) = sim.state.obstacles.get_mut(id) {
+            *obstacle = obstacle.clone().with_keyframes(keyframes);
+        }
+    }
+
+    pub fn remove_obstacle(&self, id: u32) {
+        self.sim.borrow_mut().state.obstacles.remove(id);
+    }
+
+    pub fn clear_obstacles(&self) {
+        self.sim.borrow_mut().state.obstacles.clear();
+    }
+
+    // Built-in force fields. Positions and lengths are in canvas pixels, velocities in m/s,
+    // strengths in m/s^2. Each returns the id of the field.
+    pub fn add_wind(&self, vx: f32, vy: f32, drag: f32) -> u32 {
+        let velocity = glam::Vec2::new(vx, vy);
+        self.sim.borrow_mut().state.add_force_field(Box::new(force_field::Wind { velocity, drag }))
+    }
+
+    pub fn add_vortex(&self, x: f32, y: f32, strength: f32, radius: f32) -> u32 {
+        let mut sim = self.sim.borrow_mut();
+        let center = sim.canvas_to_field(x, y);
+        let radius = radius / sim.scale;
+        sim.state.add_force_field(Box::new(force_field::Vortex { center, strength, radius }))
+    }
+
+    pub fn add_radial(&self, x: f32, y: f32, strength: f32, radius: f32) -> u32 {
+        let mut sim = self.sim.borrow_mut();
+        let center = sim.canvas_to_field(x, y);
+        let radius = radius / sim.scale;
+        sim.state.add_force_field(Box::new(force_field::Radial { center, strength, radius }))
+    }
+
+    pub fn add_noise(&self, amplitude: f32, scale: f32, speed: f32) -> u32 {
+        let mut sim = self.sim.borrow_mut();
+        let scale = scale / sim.scale;
+        sim.state.add_force_field(Box::new(force_field::Noise { amplitude, scale, speed }))
+    }
+
+    pub fn remove_force_field(&self, id: u32) {
+        self.sim.borrow_mut().state.remove_force_field(id);
+    }
+
+    pub fn clear_force_fields(&self) {
+        self.sim.borrow_mut().state.clear_force_fields();
+    }
 }
 
-fn get_canvas_element_by_id(id: &str) -> Result<web_sys::HtmlCanvasElement, JsValue> {
-    let document = window().document().unwrap();
-    document.get_element_by_id(id)
-        .ok_or(JsValue::from("Element doesn't exist."))?
-        .dyn_into::<web_sys::HtmlCanvasElement>()
-        .or_else(|e| Err(JsValue::from(e)))
+fn window() -> web_sys::Window {
+    web_sys::window().expect("no global `window` exists")
 }
 
-fn hsv_to_rgb(h: f32, s: f32, v: f32) -> (f32, f32, f32, f32) {
-    let i = (h * 6.0).floor() as u32;
-    let f = h * 6.0 - i as f32;
-    let p = v * (1.0 - s);
-    let q = v * (1.0 - f * s);
-    let t = v * (1.0 - (1.0 - f) * s);
-
-    let (mut r, mut g, mut b) = (0.0, 0.0, 0.0);
-    
-    match i % 6 {
-        0 => { r = v; g = t; b = p; }
-        1 => { r = q; g = v; b = p; }
-        2 => { r = p; g = v; b = t; }
-        3 => { r = p; g = q; b = v; }
-        4 => { r = t; g = p; b = v; }
-        5 => { r = v; g = p; b = q; }
-        _ => {}
-    }
-
-    (r, g, b, 1.0)
+// Displayed size of the canvas, or its attributes when it isn't laid out (yet)
+fn get_canvas_size(canvas: &web_sys::HtmlCanvasElement) -> CanvasSize {
+    let (width, height) = (canvas.client_width(), canvas.client_height());
+    if width > 0 && height > 0 {
+        CanvasSize { width: width as f32, height: height as f32 }
+    } else {
+        CanvasSize { width: canvas.width() as f32, height: canvas.height() as f32 }
+    }
 }
 
-fn get_color_by_speed(speed: f32) -> (f32, f32, f32, f32) {
-    let normalized_speed = (speed.abs() / MAX_SPEED).min(1.0);
-    let hue = (1.0 - normalized_speed) * 0.7;
-    let saturation = 1.0;
-    let value = 1.0;
-    hsv_to_rgb(hue, saturation, value)
+fn fade(color: (f32, f32, f32), background: (f32, f32, f32), t: f32) -> (f32, f32, f32) {
+    let lerp = |a: f32, b: f32| a + (b - a) * t;
+    (lerp(color.0, background.0), lerp(color.1, background.1), lerp(color.2, background.2))
 }
 
 fn init_webgl(
     canvas: &web_sys::HtmlCanvasElement, 
-    window_size: &WindowSize, 
-) -> Result<(WebGl2RenderingContext, BufferPair), JsValue> {
-    canvas.set_height(window_size.height as u32);
-    canvas.set_width(window_size.width as u32);
+    canvas_size: &CanvasSize, 
+) -> Result<(WebGl2RenderingContext, VertexBuffer, ShaderProgram), JsValue> {
+    canvas.set_height(canvas_size.height as u32);
+    canvas.set_width(canvas_size.width as u32);
 
     let gl = canvas
         .get_context("webgl2")?
@@ -327,26 +948,27 @@ fn init_webgl(
 
     let shader_program = init_shader_program(&gl)?;
 
-    gl.clear_color(0.4, 0.4, 0.4, 1.0); 
+    gl.clear_color(BACKGROUND_COLOR.0, BACKGROUND_COLOR.1, BACKGROUND_COLOR.2, 1.0); 
     gl.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT);
 
-    let position_buffer = gl.create_buffer().unwrap();
-    let color_buffer = gl.create_buffer().unwrap();
+    let buffer = gl.create_buffer().unwrap();
 
-    set_position_attribute(&gl, &shader_program, &position_buffer)?;
-    set_color_attribute(&gl, &shader_program, &color_buffer)?;
+    set_position_attribute(&gl, &shader_program, &buffer)?;
+    set_color_attribute(&gl, &shader_program, &buffer)?;
 
     let resolution_location = gl.get_uniform_location(&shader_program, "uResolution").unwrap();
     gl.uniform2f(Some(&resolution_location), canvas.width() as f32, canvas.height() as f32);
+    let disc_location = gl.get_uniform_location(&shader_program, "uDisc").unwrap();
+    gl.uniform1i(Some(&disc_location), 1);
 
-    Ok((gl, BufferPair{ position_buffer, color_buffer }))
+    Ok((gl, VertexBuffer{ buffer, capacity: Cell::new(0) }, ShaderProgram{ program: shader_program, resolution_location, disc_location }))
 }
 
 fn init_shader_program(
     gl: &WebGl2RenderingContext, 
 ) -> Result<WebGlProgram, JsValue> {
-    let vertex_shader = compile_shader(&gl, WebGl2RenderingContext::VERTEX_SHADER, VERTEX_SHADER)?;
-    let fragment_shader = compile_shader(&gl, WebGl2RenderingContext::FRAGMENT_SHADER, FRAGMENT_SHADER)?;
+    let vertex_shader = compile_shader(gl, WebGl2RenderingContext::VERTEX_SHADER, VERTEX_SHADER)?;
+    let fragment_shader = compile_shader(gl, WebGl2RenderingContext::FRAGMENT_SHADER, FRAGMENT_SHADER)?;
 
     let program = gl.create_program().unwrap();
     gl.attach_shader(&program, &vertex_shader);
@@ -384,17 +1006,17 @@ fn set_position_attribute(
     program: &WebGlProgram, 
     buffer: &WebGlBuffer
 ) -> Result<(), JsValue>{
-    gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&buffer));
+    gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(buffer));
     let position_location = gl.get_attrib_location(program, "aPosition");
 
     if position_location >= 0 {
-        gl.vertex_attrib_pointer_with_i32(position_location as u32, 3, WebGl2RenderingContext::FLOAT, false, 0, 0);
+        gl.vertex_attrib_pointer_with_i32(position_location as u32, 3, WebGl2RenderingContext::FLOAT, false, (VERTEX_SIZE * 4) as i32, 0);
         gl.enable_vertex_attrib_array(position_location as u32);
     } else {
         return Err(JsValue::from_str("cannot set position attribute"));
     }
 
-    return Ok(());
+    Ok(())
 }
 
 fn set_color_attribute(
@@ -402,27 +1024,42 @@ fn set_color_attribute(
     program: &WebGlProgram, 
     buffer: &WebGlBuffer
 ) -> Result<(), JsValue>{
-    gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&buffer));
+    gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(buffer));
     let position_location = gl.get_attrib_location(program, "aColor");
 
     if position_location >= 0 {
-        gl.vertex_attrib_pointer_with_i32(position_location as u32, 3, WebGl2RenderingContext::FLOAT, false, 0, 0);
+        // The colour follows the position and the point size
+        gl.vertex_attrib_pointer_with_i32(position_location as u32, 3, WebGl2RenderingContext::FLOAT, false, (VERTEX_SIZE * 4) as i32, 3 * 4);
         gl.enable_vertex_attrib_array(position_location as u32);
     } else {
         return Err(JsValue::from_str("cannot set color attribute"));
     }
 
-    return Ok(());
+    Ok(())
+}
+
+
+// Registered listener that is removed again when dropped, together with the simulation that owns it,
+// so that a freed simulation leaves no closures behind on the canvas or the window
+#[derive(Debug)]
+struct EventListener {
+    target: web_sys::EventTarget, 
+    event_name: String, 
+    callback: Closure<dyn FnMut(web_sys::Event)>, 
 }
 
+impl Drop for EventListener {
+    fn drop(&mut self) {
+        let _ = self.target.remove_event_listener_with_callback(&self.event_name, self.callback.as_ref().unchecked_ref());
+    }
+}
 
-fn add_event_listener<T>(target: &web_sys::Element, event_name: &str, handler: T) -> Result<(), JsValue>
+fn add_event_listener<T>(target: &web_sys::EventTarget, event_name: &str, handler: T) -> Result<EventListener, JsValue>
 where
     T: 'static + FnMut(web_sys::Event)
 {
-    let cb = Closure::wrap(Box::new(handler) as Box<dyn FnMut(_)>);
-    target.add_event_listener_with_callback(event_name, cb.as_ref().unchecked_ref())?;
-    cb.forget();
+    let callback = Closure::wrap(Box::new(handler) as Box<dyn FnMut(_)>);
+    target.add_event_listener_with_callback(event_name, callback.as_ref().unchecked_ref())?;
 
-    Ok(())
+    Ok(EventListener { target: target.clone(), event_name: event_name.to_string(), callback })
 }
\ No newline at end of file
@@ -0,0 +1,206 @@
+use wasm_bindgen::prelude::*;
+use web_sys::{WebGl2RenderingContext, WebGlFramebuffer, WebGlProgram, WebGlTexture, WebGlUniformLocation};
+
+use crate::compile_shader;
+
+// Screen-space metaballs. The particles are splatted as soft Gaussian blobs into an offscreen
+// framebuffer (colour premultiplied by the weight in rgb, weight in alpha), then a full-screen pass
+// thresholds the summed weight and shades the result as one continuous body.
+
+// Same attributes as the particle shader, the point sprite covers three standard deviations
+static BLOB_VERTEX_SHADER: &str = r#"
+    attribute vec3 aPosition;
+    attribute vec3 aColor;
+    varying vec3 vColor;
+    uniform vec2 uResolution;
+
+    void main() {
+        vec2 position = (aPosition.xy / uResolution) * 2.0 - 1.0;
+        gl_Position = vec4(position, 0, 1);
+        gl_PointSize = aPosition.z * 6.0;
+        vColor = aColor;
+    }
+"#;
+
+// Inside a fluid at rest the weights sum up to about 2π * BLOB_AMPLITUDE
+static BLOB_FRAGMENT_SHADER: &str = r#"
+    precision mediump float;
+    varying vec3 vColor;
+    const float BLOB_AMPLITUDE = 0.1;
+    void main() {
+        vec2 pos = gl_PointCoord * 2.0 - 1.0;
+        float r2 = dot(pos, pos);
+        if (r2 > 1.0) {
+            discard;
+        }
+        float weight = BLOB_AMPLITUDE * exp(-4.5 * r2);
+        gl_FragColor = vec4(vColor * weight, weight);
+    }
+"#;
+
+// A single triangle covering the screen, no vertex attributes needed
+static COMPOSITE_VERTEX_SHADER: &str = r#"#version 300 es
+    void main() {
+        vec2 position = vec2(float((gl_VertexID & 1) << 2), float((gl_VertexID & 2) << 1)) - 1.0;
+        gl_Position = vec4(position, 0, 1);
+    }
+"#;
+
+static COMPOSITE_FRAGMENT_SHADER: &str = r#"#version 300 es
+    precision mediump float;
+    uniform sampler2D uField;
+    out vec4 fragColor;
+    const float THRESHOLD = 0.3;
+    const float EDGE_WIDTH = 0.04;
+    const vec3 LIGHT = vec3(-0.38, 0.57, 0.73);
+
+    // Clamped, texelFetch outside the texture is undefined
+    float weight(ivec2 p) {
+        return texelFetch(uField, clamp(p, ivec2(0), textureSize(uField, 0) - 1), 0).a;
+    }
+
+    void main() {
+        ivec2 p = ivec2(gl_FragCoord.xy);
+        vec4 field = texelFetch(uField, p, 0);
+        float alpha = smoothstep(THRESHOLD - EDGE_WIDTH, THRESHOLD + EDGE_WIDTH, field.a);
+        if (alpha <= 0.0) {
+            discard;
+        }
+        vec3 color = field.rgb / max(field.a, 1e-4);
+
+        // The summed weight is treated as a height field for the lighting
+        float dx = weight(p + ivec2(1, 0)) - weight(p - ivec2(1, 0));
+        float dy = weight(p + ivec2(0, 1)) - weight(p - ivec2(0, 1));
+        vec3 normal = normalize(vec3(-dx, -dy, 0.15));
+        float diffuse = 0.65 + 0.35 * max(dot(normal, LIGHT), 0.0);
+        float specular = pow(max(dot(reflect(-LIGHT, normal), vec3(0.0, 0.0, 1.0)), 0.0), 24.0);
+
+        // Thicker parts absorb more light and look deeper
+        float thickness = clamp((field.a - THRESHOLD) / (1.0 - THRESHOLD), 0.0, 1.0);
+        vec3 tint = color * diffuse * mix(1.0, 0.7, thickness);
+        fragColor = vec4(tint + 0.6 * specular, alpha);
+    }
+"#;
+
+pub struct Metaballs {
+    blob_program: WebGlProgram, 
+    blob_resolution_location: WebGlUniformLocation, 
+    composite_program: WebGlProgram, 
+    framebuffer: WebGlFramebuffer, 
+    texture: WebGlTexture, 
+    // Half floats keep the faint tails of the blobs, 8 bit is the fallback
+    half_float: bool, 
+    // Attribute locations of the particle shader, shared by the blob shader
+    attributes: [u32; 2], 
+}
+
+impl Metaballs {
+    pub fn new(gl: &WebGl2RenderingContext, particle_program: &WebGlProgram, width: i32, height: i32) -> Result<Self, JsValue> {
+        let position_location = gl.get_attrib_location(particle_program, "aPosition");
+        let color_location = gl.get_attrib_location(particle_program, "aColor");
+        if position_location < 0 || color_location < 0 {
+            return Err(JsValue::from_str("cannot find particle attributes"));
+        }
+        let attributes = [position_location as u32, color_location as u32];
+
+        let blob_program = link_program(gl, BLOB_VERTEX_SHADER, BLOB_FRAGMENT_SHADER, &[(attributes[0], "aPosition"), (attributes[1], "aColor")])?;
+        let blob_resolution_location = gl.get_uniform_location(&blob_program, "uResolution")
+            .ok_or(JsValue::from_str("cannot find uResolution"))?;
+        let composite_program = link_program(gl, COMPOSITE_VERTEX_SHADER, COMPOSITE_FRAGMENT_SHADER, &[])?;
+
+        let half_float = gl.get_extension("EXT_color_buffer_float")?.is_some();
+        let framebuffer = gl.create_framebuffer().ok_or(JsValue::from_str("cannot create framebuffer"))?;
+        let texture = gl.create_texture().ok_or(JsValue::from_str("cannot create texture"))?;
+
+        let metaballs = Metaballs { blob_program, blob_resolution_location, composite_program, framebuffer, texture, half_float, attributes };
+        metaballs.resize(gl, particle_program, width, height)?;
+        Ok(metaballs)
+    }
+
+    // The offscreen buffer has the size of the canvas, at least one pixel so that a hidden canvas
+    // still gives a complete framebuffer. Fails when neither format is renderable.
+    pub fn resize(&self, gl: &WebGl2RenderingContext, particle_program: &WebGlProgram, width: i32, height: i32) -> Result<(), JsValue> {
+        let (width, height) = (width.max(1), height.max(1));
+        let mut formats = vec![(WebGl2RenderingContext::RGBA8, WebGl2RenderingContext::UNSIGNED_BYTE)];
+        if self.half_float {
+            formats.insert(0, (WebGl2RenderingContext::RGBA16F, WebGl2RenderingContext::HALF_FLOAT));
+        }
+        let mut status = 0;
+        for (internal_format, data_type) in formats {
+            gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&self.texture));
+            gl.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+                WebGl2RenderingContext::TEXTURE_2D, 0, internal_format as i32, width, height, 0,
+                WebGl2RenderingContext::RGBA, data_type, None
+            )?;
+            gl.tex_parameteri(WebGl2RenderingContext::TEXTURE_2D, WebGl2RenderingContext::TEXTURE_MIN_FILTER, WebGl2RenderingContext::NEAREST as i32);
+            gl.tex_parameteri(WebGl2RenderingContext::TEXTURE_2D, WebGl2RenderingContext::TEXTURE_MAG_FILTER, WebGl2RenderingContext::NEAREST as i32);
+
+            gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, Some(&self.framebuffer));
+            gl.framebuffer_texture_2d(
+                WebGl2RenderingContext::FRAMEBUFFER, WebGl2RenderingContext::COLOR_ATTACHMENT0,
+                WebGl2RenderingContext::TEXTURE_2D, Some(&self.texture), 0
+            );
+            status = gl.check_framebuffer_status(WebGl2RenderingContext::FRAMEBUFFER);
+            gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+            if status == WebGl2RenderingContext::FRAMEBUFFER_COMPLETE {
+                break;
+            }
+        }
+        if status != WebGl2RenderingContext::FRAMEBUFFER_COMPLETE {
+            return Err(JsValue::from_str(&format!("incomplete framebuffer: 0x{:x}", status)));
+        }
+
+        gl.use_program(Some(&self.blob_program));
+        gl.uniform2f(Some(&self.blob_resolution_location), width as f32, height as f32);
+        gl.use_program(Some(particle_program));
+        Ok(())
+    }
+
+    // Draws the first `count` vertices of the bound vertex buffer. Leaves the particle program in use.
+    pub fn draw(&self, gl: &WebGl2RenderingContext, particle_program: &WebGlProgram, count: i32) {
+        gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, Some(&self.framebuffer));
+        gl.clear_color(0.0, 0.0, 0.0, 0.0);
+        gl.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT);
+        gl.use_program(Some(&self.blob_program));
+        gl.blend_func(WebGl2RenderingContext::ONE, WebGl2RenderingContext::ONE);
+        gl.draw_arrays(WebGl2RenderingContext::POINTS, 0, count);
+        gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+
+        gl.use_program(Some(&self.composite_program));
+        gl.blend_func(WebGl2RenderingContext::SRC_ALPHA, WebGl2RenderingContext::ONE_MINUS_SRC_ALPHA);
+        gl.active_texture(WebGl2RenderingContext::TEXTURE0);
+        gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&self.texture));
+        // The vertex buffer may hold fewer than three vertices
+        for location in self.attributes {
+            gl.disable_vertex_attrib_array(location);
+        }
+        gl.draw_arrays(WebGl2RenderingContext::TRIANGLES, 0, 3);
+        for location in self.attributes {
+            gl.enable_vertex_attrib_array(location);
+        }
+        gl.use_program(Some(particle_program));
+    }
+}
+
+fn link_program(gl: &WebGl2RenderingContext, vertex_source: &str, fragment_source: &str, attributes: &[(u32, &str)]) -> Result<WebGlProgram, JsValue> {
+    let vertex_shader = compile_shader(gl, WebGl2RenderingContext::VERTEX_SHADER, vertex_source)?;
+    let fragment_shader = compile_shader(gl, WebGl2RenderingContext::FRAGMENT_SHADER, fragment_source)?;
+
+    let program = gl.create_program().ok_or(JsValue::from_str("cannot create program"))?;
+    gl.attach_shader(&program, &vertex_shader);
+    gl.attach_shader(&program, &fragment_shader);
+    for (location, name) in attributes {
+        gl.bind_attrib_location(&program, *location, name);
+    }
+    gl.link_program(&program);
+
+    let success = gl
+        .get_program_parameter(&program, WebGl2RenderingContext::LINK_STATUS)
+        .as_bool()
+        .unwrap_or(false);
+    if !success {
+        let error_msg = gl.get_program_info_log(&program).unwrap_or_else(|| "Unknown error".into());
+        return Err(JsValue::from_str(&error_msg));
+    }
+    Ok(program)
+}
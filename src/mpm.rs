@@ -0,0 +1,321 @@
+use glam::{Mat2, Vec2};
+use rayon::prelude::*;
+
+use crate::solver::{Field, Material, Particle, PARTICLE_SIZE};
+
+// Moving Least Squares MPM (Hu et al. 2018) on a quadratic B-spline grid.
+// Particle positions and velocities are shared with the SPH solver,
+// while the MPM specific per-particle quantities live here in arrays parallel to `State::particles`.
+
+pub const MPM_DT: f32 = 1.25e-4;
+pub const MPM_STEPS: u32 = 80;
+const YOUNG_MODULUS: f32 = 1.0e3;
+const POISSON_RATIO: f32 = 0.2;
+const MU_0: f32 = YOUNG_MODULUS / (2.0 * (1.0 + POISSON_RATIO));
+const LAMBDA_0: f32 = YOUNG_MODULUS * POISSON_RATIO / ((1.0 + POISSON_RATIO) * (1.0 - 2.0 * POISSON_RATIO));
+const RHO: f32 = 1.0;
+// Snow plasticity (Stomakhin et al. 2013)
+const CRITICAL_COMPRESSION: f32 = 2.5e-2;
+const CRITICAL_STRETCH: f32 = 4.5e-3;
+const HARDENING: f32 = 10.0;
+const JELLY_SOFTNESS: f32 = 0.3;
+// Drucker-Prager sand (Klár et al. 2016): sqrt(2/3) * 2 sin(φ) / (3 - sin(φ)) for a friction angle φ of 35°
+const SAND_ALPHA: f32 = 0.386;
+// Grid rows per stripe of the parallel scatter, at least the two extra rows a stencil reaches
+const P2G_STRIPE_ROWS: usize = 4;
+const BOUNDARY_NODES: usize = 3;
+const SAND_FLOOR_FRICTION: f32 = 0.5;
+
+pub struct Mpm {
+    nx: usize, 
+    ny: usize, 
+    dx: f32, 
+    grid_velocity: Vec<Vec2>, 
+    grid_mass: Vec<f32>, 
+    // Part of the node mass that is sand, for the floor friction
+    grid_sand_mass: Vec<f32>, 
+    // Per-particle deformation gradient, APIC affine velocity and plastic volume change
+    deformation: Vec<Mat2>, 
+    affine: Vec<Mat2>, 
+    plastic_j: Vec<f32>, 
+    // Particle indices by stripe of their base row, reused between steps
+    stripes: Vec<Vec<u32>>, 
+}
+
+impl Mpm {
+    pub fn new(field: &Field, dx: f32) -> Self {
+        let nx = (field.width / dx).ceil() as usize + 1;
+        let ny = (field.height / dx).ceil() as usize + 1;
+        Mpm {
+            nx,
+            ny,
+            dx,
+            grid_velocity: vec![Vec2::ZERO; nx * ny],
+            grid_mass: vec![0.0; nx * ny],
+            grid_sand_mass: vec![0.0; nx * ny],
+            deformation: Vec::new(),
+            affine: Vec::new(),
+            plastic_j: Vec::new(),
+            stripes: Vec::new(),
+        }
+    }
+
+    // New grid for the field, the per-particle state is kept
+    pub fn resize(&mut self, field: &Field) {
+        let grid = Mpm::new(field, self.dx);
+        self.nx = grid.nx;
+        self.ny = grid.ny;
+        self.grid_velocity = grid.grid_velocity;
+        self.grid_mass = grid.grid_mass;
+        self.grid_sand_mass = grid.grid_sand_mass;
+    }
+
+    pub fn clear(&mut self) {
+        self.deformation.clear();
+        self.affine.clear();
+        self.plastic_j.clear();
+    }
+
+    pub fn push_particle(&mut self) {
+        self.deformation.push(Mat2::IDENTITY);
+        self.affine.push(Mat2::ZERO);
+        self.plastic_j.push(1.0);
+    }
+
+    // Drops the per-particle state of the particles whose `keep` entry is false
+    pub fn retain(&mut self, keep: &[bool]) {
+        let mut keep_iter = keep.iter();
+        self.deformation.retain(|_| *keep_iter.next().unwrap());
+        let mut keep_iter = keep.iter();
+        self.affine.retain(|_| *keep_iter.next().unwrap());
+        let mut keep_iter = keep.iter();
+        self.plastic_j.retain(|_| *keep_iter.next().unwrap());
+    }
+
+    pub fn step(&mut self, particles: &mut [Particle], field: &Field, gravity: Vec2, dt: f32) {
+        self.particle_to_grid(particles, dt);
+        self.update_grid(gravity, dt);
+        self.grid_to_particle(particles, field, dt);
+    }
+
+    fn particle_to_grid(&mut self, particles: &[Particle], dt: f32) {
+        let inv_dx = 1.0 / self.dx;
+        let particle_volume = PARTICLE_SIZE * PARTICLE_SIZE;
+        let particle_mass = RHO * particle_volume;
+
+        // Deformation update and constitutive model are independent per particle
+        let stresses: Vec<Mat2> = particles
+            .par_iter()
+            .zip_eq(self.deformation.par_iter_mut())
+            .zip_eq(self.affine.par_iter())
+            .zip_eq(self.plastic_j.par_iter_mut())
+            .map(|(((particle, f), c), jp)| {
+                *f = (Mat2::IDENTITY + dt * *c) * *f;
+                let stress = kirchhoff_stress(particle.material, f, jp);
+                (-dt * particle_volume * 4.0 * inv_dx * inv_dx) * stress + particle_mass * *c
+            })
+            .collect();
+
+        // Particles are binned into horizontal stripes of grid rows. A particle writes to the three rows
+        // from its base row on, so every other stripe can scatter in parallel without overlapping.
+        let (nx, ny, dx) = (self.nx, self.ny, self.dx);
+        let num_stripes = ny.div_ceil(P2G_STRIPE_ROWS);
+        self.stripes.resize_with(num_stripes, Vec::new);
+        self.stripes.iter_mut().for_each(|stripe| stripe.clear());
+        for (i, particle) in particles.iter().enumerate() {
+            let ((_, base_y), _, _) = stencil(particle.position, dx, nx, ny);
+            self.stripes[base_y / P2G_STRIPE_ROWS].push(i as u32);
+        }
+
+        self.grid_velocity.par_iter_mut().for_each(|v| *v = Vec2::ZERO);
+        self.grid_mass.par_iter_mut().for_each(|m| *m = 0.0);
+        self.grid_sand_mass.par_iter_mut().for_each(|m| *m = 0.0);
+        let stripes = &self.stripes;
+        let stripe_len = P2G_STRIPE_ROWS * nx;
+        for parity in 0..2 {
+            // Chunk k holds stripes 2k + parity and 2k + parity + 1, the first one scatters into both
+            let offset = (parity * stripe_len).min(nx * ny);
+            self.grid_velocity[offset..].par_chunks_mut(2 * stripe_len)
+                .zip(self.grid_mass[offset..].par_chunks_mut(2 * stripe_len))
+                .zip(self.grid_sand_mass[offset..].par_chunks_mut(2 * stripe_len))
+                .enumerate()
+                .for_each(|(k, ((momentum, mass), sand_mass))|{
+                    let stripe = 2 * k + parity;
+                    let first_node = stripe * stripe_len;
+                    for &i in &stripes[stripe] {
+                        let particle = &particles[i as usize];
+                        let stress = stresses[i as usize];
+                        let is_sand = particle.material == Material::Sand;
+                        let (base, fx, weights) = stencil(particle.position, dx, nx, ny);
+                        for (ox, wx) in weights.iter().enumerate() {
+                            for (oy, wy) in weights.iter().enumerate() {
+                                let weight = wx.x * wy.y;
+                                let dpos = (Vec2::new(ox as f32, oy as f32) - fx) * dx;
+                                let id = (base.1 + oy) * nx + base.0 + ox - first_node;
+                                momentum[id] += weight * (particle_mass * particle.velocity + stress * dpos);
+                                mass[id] += weight * particle_mass;
+                                if is_sand {
+                                    sand_mass[id] += weight * particle_mass;
+                                }
+                            }
+                        }
+                    }
+                });
+        }
+    }
+
+    fn update_grid(&mut self, gravity: Vec2, dt: f32) {
+        let nx = self.nx;
+        let ny = self.ny;
+        self.grid_velocity
+            .par_iter_mut()
+            .zip_eq(self.grid_mass.par_iter())
+            .zip_eq(self.grid_sand_mass.par_iter())
+            .enumerate()
+            .for_each(|(id, ((v, m), sand_m))|{
+                if *m <= 0.0 {
+                    return;
+                }
+                *v = *v / *m + dt * gravity;
+
+                let (i, j) = (id % nx, id / nx);
+                if i < BOUNDARY_NODES && v.x < 0.0 { v.x = 0.0; }
+                if i + BOUNDARY_NODES >= nx && v.x > 0.0 { v.x = 0.0; }
+                if j < BOUNDARY_NODES && v.y < 0.0 {
+                    // Coulomb friction of the sand with the floor, without it a pile spreads out flat
+                    let friction = SAND_FLOOR_FRICTION * sand_m / m;
+                    let vx = v.x;
+                    v.x = vx.signum() * (vx.abs() + friction * v.y).max(0.0);
+                    v.y = 0.0;
+                }
+                if j + BOUNDARY_NODES >= ny && v.y > 0.0 { v.y = 0.0; }
+            });
+    }
+
+    fn grid_to_particle(&mut self, particles: &mut [Particle], field: &Field, dt: f32) {
+        let inv_dx = 1.0 / self.dx;
+        let margin = self.dx;
+        let grid: &Self = self;
+        let affine: Vec<Mat2> = particles
+            .par_iter_mut()
+            .map(|particle|{
+                let (base, fx, weights) = grid.stencil(particle.position);
+                let mut velocity = Vec2::ZERO;
+                let mut c = Mat2::ZERO;
+                for (ox, wx) in weights.iter().enumerate() {
+                    for (oy, wy) in weights.iter().enumerate() {
+                        let weight = wx.x * wy.y;
+                        let dpos = Vec2::new(ox as f32, oy as f32) - fx;
+                        let grid_v = grid.grid_velocity[grid.node_id(base.0 + ox, base.1 + oy)];
+                        velocity += weight * grid_v;
+                        c += (4.0 * inv_dx * weight) * Mat2::from_cols(grid_v * dpos.x, grid_v * dpos.y);
+                    }
+                }
+                particle.velocity = velocity;
+                particle.position += dt * velocity;
+                particle.position = particle.position.clamp(
+                    Vec2::splat(margin),
+                    Vec2::new(field.width - margin, field.height - margin)
+                );
+                c
+            })
+            .collect();
+        self.affine = affine;
+    }
+
+    // Base node, fractional position and quadratic B-spline weights (packed as (wx, wy) per offset)
+    fn stencil(&self, position: Vec2) -> ((usize, usize), Vec2, [Vec2; 3]) {
+        stencil(position, self.dx, self.nx, self.ny)
+    }
+
+    fn node_id(&self, i: usize, j: usize) -> usize {
+        j * self.nx + i
+    }
+}
+
+fn stencil(position: Vec2, dx: f32, nx: usize, ny: usize) -> ((usize, usize), Vec2, [Vec2; 3]) {
+    let xp = position / dx;
+    let base_x = ((xp.x - 0.5) as usize).min(nx - 3);
+    let base_y = ((xp.y - 0.5) as usize).min(ny - 3);
+    let fx = xp - Vec2::new(base_x as f32, base_y as f32);
+    let weights = [
+        0.5 * (Vec2::splat(1.5) - fx).powf(2.0),
+        Vec2::splat(0.75) - (fx - Vec2::ONE).powf(2.0),
+        0.5 * (fx - Vec2::splat(0.5)).powf(2.0),
+    ];
+    ((base_x, base_y), fx, weights)
+}
+
+// Fixed-corotated elasticity with material dependent plasticity. Updates `f` and `jp` in place.
+fn kirchhoff_stress(material: Material, f: &mut Mat2, jp: &mut f32) -> Mat2 {
+    let (mu, lambda) = match material {
+        Material::Liquid => (0.0, LAMBDA_0),
+        Material::Jelly => (MU_0 * JELLY_SOFTNESS, LAMBDA_0 * JELLY_SOFTNESS),
+        Material::Sand => (MU_0, LAMBDA_0),
+        Material::Snow => {
+            let h = (HARDENING * (1.0 - *jp)).exp();
+            (MU_0 * h, LAMBDA_0 * h)
+        }
+    };
+
+    let (u, mut sigma, v) = svd(*f);
+    match material {
+        Material::Snow => {
+            let clamped = sigma.clamp(Vec2::splat(1.0 - CRITICAL_COMPRESSION), Vec2::splat(1.0 + CRITICAL_STRETCH));
+            *jp *= sigma.x * sigma.y / (clamped.x * clamped.y);
+            sigma = clamped;
+        }
+        Material::Sand => sigma = project_sand(sigma, mu, lambda),
+        Material::Liquid | Material::Jelly => {}
+    }
+    let j = sigma.x * sigma.y;
+
+    match material {
+        // Liquids forget their shape and only keep the volume change
+        Material::Liquid => *f = Mat2::IDENTITY * j.sqrt(),
+        Material::Snow | Material::Sand => *f = u * Mat2::from_diagonal(sigma) * v.transpose(),
+        Material::Jelly => {}
+    }
+
+    let r = u * v.transpose();
+    2.0 * mu * (*f - r) * f.transpose() + Mat2::IDENTITY * (lambda * (j - 1.0) * j)
+}
+
+// Return mapping of the Drucker-Prager yield condition in log strain space. Stretched grains lose
+// contact and are stress free, sheared grains slip until the shear is within the friction cone.
+fn project_sand(sigma: Vec2, mu: f32, lambda: f32) -> Vec2 {
+    let epsilon = Vec2::new(sigma.x.max(1e-6).ln(), sigma.y.max(1e-6).ln());
+    let trace = epsilon.x + epsilon.y;
+    if trace >= 0.0 {
+        return Vec2::ONE;
+    }
+    let deviator = epsilon - Vec2::splat(0.5 * trace);
+    let deviator_norm = deviator.length();
+    let delta_gamma = deviator_norm + (2.0 * lambda + 2.0 * mu) / (2.0 * mu) * trace * SAND_ALPHA;
+    if delta_gamma <= 0.0 || deviator_norm < 1e-12 {
+        return sigma;
+    }
+    (epsilon - delta_gamma / deviator_norm * deviator).exp()
+}
+
+// Singular value decomposition of a 2x2 matrix: f = u * diag(sigma) * v^T
+fn svd(f: Mat2) -> (Mat2, Vec2, Mat2) {
+    // Polar decomposition f = r * s
+    let x = f.x_axis.x + f.y_axis.y;
+    let y = f.x_axis.y - f.y_axis.x;
+    let norm = (x * x + y * y).sqrt();
+    let (c, s) = if norm > 1e-12 { (x / norm, y / norm) } else { (1.0, 0.0) };
+    let r = Mat2::from_cols(Vec2::new(c, s), Vec2::new(-s, c));
+    let sym = r.transpose() * f;
+
+    // Eigen decomposition of the symmetric part
+    let (s11, s12, s22) = (sym.x_axis.x, sym.y_axis.x, sym.y_axis.y);
+    let theta = 0.5 * (2.0 * s12).atan2(s11 - s22);
+    let (ct, st) = (theta.cos(), theta.sin());
+    let v = Mat2::from_cols(Vec2::new(ct, st), Vec2::new(-st, ct));
+    let sigma = Vec2::new(
+        ct * ct * s11 + 2.0 * ct * st * s12 + st * st * s22,
+        st * st * s11 - 2.0 * ct * st * s12 + ct * ct * s22,
+    );
+    (r * v, sigma, v)
+}
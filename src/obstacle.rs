@@ -0,0 +1,295 @@
+use glam::{Mat2, Vec2};
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+// Rigid obstacles moved by keyframes or from outside (JS), never by the fluid.
+// Particles are pushed out of them and take over the obstacle velocity at the contact point.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    Circle { radius: f32 }, 
+    Box { half_extents: Vec2 }, 
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Keyframe {
+    pub time: f32, 
+    pub position: Vec2, 
+    pub angle: f32, 
+}
+
+#[derive(Clone, Debug)]
+enum Motion {
+    Static, 
+    // Looped, the last keyframe time is the period. Carries its own clock.
+    Keyframes { keyframes: Vec<Keyframe>, time: f32 }, 
+    // Moves with constant velocity towards the last transform set from outside and stops there
+    Scripted { position: Vec2, angle: f32, remaining: f32 }, 
+}
+
+#[derive(Clone, Debug)]
+pub struct Obstacle {
+    pub shape: Shape, 
+    pub position: Vec2, 
+    pub angle: f32, 
+    pub velocity: Vec2, 
+    pub angular_velocity: f32, 
+    motion: Motion, 
+    // Spacing and points of the last `sample_points`, cleared when the obstacle moves
+    samples: OnceLock<(f32, Vec<Vec2>)>, 
+}
+
+impl Obstacle {
+    pub fn new(shape: Shape, position: Vec2, angle: f32) -> Self {
+        Obstacle { shape, position, angle, velocity: Vec2::ZERO, angular_velocity: 0.0, motion: Motion::Static, samples: OnceLock::new() }
+    }
+
+    pub fn with_keyframes(mut self, mut keyframes: Vec<Keyframe>) -> Self {
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        if let Some(first) = keyframes.first() {
+            self.position = first.position;
+            self.angle = first.angle;
+            self.samples.take();
+        }
+        self.motion = Motion::Keyframes { keyframes, time: 0.0 };
+        self
+    }
+
+    // The obstacle reaches the transform after `duration` seconds of simulated time (usually one frame)
+    pub fn set_transform(&mut self, position: Vec2, angle: f32, duration: f32) {
+        if duration <= 0.0 {
+            self.position = position;
+            self.angle = angle;
+            self.stop();
+            return;
+        }
+        self.velocity = (position - self.position) / duration;
+        self.angular_velocity = (angle - self.angle) / duration;
+        self.motion = Motion::Scripted { position, angle, remaining: duration };
+    }
+
+    pub fn advance(&mut self, dt: f32) {
+        match &mut self.motion {
+            Motion::Static => return,
+            Motion::Scripted { position, angle, remaining } => {
+                if *remaining <= dt {
+                    // Lands exactly on the target instead of overshooting when no new transform comes
+                    self.position = *position;
+                    self.angle = *angle;
+                    self.stop();
+                } else {
+                    *remaining -= dt;
+                    self.position += self.velocity * dt;
+                    self.angle += self.angular_velocity * dt;
+                }
+            }
+            Motion::Keyframes { keyframes, time } => {
+                let (last_position, last_angle) = sample_keyframes(keyframes, *time);
+                *time += dt;
+                let (position, angle) = sample_keyframes(keyframes, *time);
+                self.velocity = (position - last_position) / dt;
+                self.angular_velocity = (angle - last_angle) / dt;
+                self.position = position;
+                self.angle = angle;
+            }
+        }
+        self.samples.take();
+    }
+
+    fn stop(&mut self) {
+        self.velocity = Vec2::ZERO;
+        self.angular_velocity = 0.0;
+        self.motion = Motion::Static;
+        self.samples.take();
+    }
+
+    // Signed distance to the surface (negative inside) and the outward normal
+    pub fn signed_distance(&self, point: Vec2) -> (f32, Vec2) {
+        let rotation = Mat2::from_angle(self.angle);
+        let local = rotation.transpose() * (point - self.position);
+        let (distance, normal) = match self.shape {
+            Shape::Circle { radius } => (local.length() - radius, local.normalize_or_zero()),
+            Shape::Box { half_extents } => {
+                let q = local.abs() - half_extents;
+                if q.x > 0.0 || q.y > 0.0 {
+                    let outside = q.max(Vec2::ZERO);
+                    (outside.length(), (outside * local.signum()).normalize_or_zero())
+                } else if q.x > q.y {
+                    (q.x, Vec2::new(local.x.signum(), 0.0))
+                } else {
+                    (q.y, Vec2::new(0.0, local.y.signum()))
+                }
+            }
+        };
+        (distance, rotation * normal)
+    }
+
+    pub fn velocity_at(&self, point: Vec2) -> Vec2 {
+        self.velocity + self.angular_velocity * (point - self.position).perp()
+    }
+
+    // Pushes a particle out to `margin` from the surface and removes its velocity into the obstacle.
+    // `restitution` is the fraction of the normal velocity that bounces back, as at the walls.
+    pub fn collide(&self, position: &mut Vec2, velocity: &mut Vec2, margin: f32, restitution: f32) {
+        let (distance, normal) = self.signed_distance(*position);
+        if distance >= margin {
+            return;
+        }
+        *position += (margin - distance) * normal;
+        let obstacle_velocity = self.velocity_at(*position);
+        let relative = *velocity - obstacle_velocity;
+        let normal_speed = relative.dot(normal);
+        if normal_speed < 0.0 {
+            *velocity = obstacle_velocity + relative - (1.0 + restitution) * normal_speed * normal;
+        }
+    }
+
+    // Points covering the obstacle, for drawing it with the particles. Cached until the obstacle moves,
+    // later calls with the same spacing borrow them without allocating.
+    pub fn sample_points(&self, spacing: f32) -> Cow<'_, [Vec2]> {
+        let (cached_spacing, points) = self.samples.get_or_init(|| (spacing, self.cover(spacing)));
+        if *cached_spacing == spacing {
+            Cow::Borrowed(points)
+        } else {
+            Cow::Owned(self.cover(spacing))
+        }
+    }
+
+    fn cover(&self, spacing: f32) -> Vec<Vec2> {
+        let extents = match self.shape {
+            Shape::Circle { radius } => Vec2::splat(radius),
+            Shape::Box { half_extents } => half_extents,
+        };
+        let rotation = Mat2::from_angle(self.angle);
+        let nx = (extents.x / spacing).ceil() as i32;
+        let ny = (extents.y / spacing).ceil() as i32;
+        let mut points = Vec::new();
+        for j in -ny..=ny {
+            for i in -nx..=nx {
+                let local = Vec2::new(i as f32, j as f32) * spacing;
+                let point = self.position + rotation * local;
+                if self.signed_distance(point).0 <= 0.0 {
+                    points.push(point);
+                }
+            }
+        }
+        points
+    }
+}
+
+// Linear interpolation between the keyframes
+fn sample_keyframes(keyframes: &[Keyframe], time: f32) -> (Vec2, f32) {
+    let (first, last) = match (keyframes.first(), keyframes.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return (Vec2::ZERO, 0.0),
+    };
+    if keyframes.len() == 1 || last.time <= 0.0 {
+        return (last.position, last.angle);
+    }
+    let cycles = (time / last.time).floor();
+    let t = time - cycles * last.time;
+    let next = keyframes.iter().position(|keyframe| keyframe.time > t).unwrap_or(keyframes.len() - 1).max(1);
+    let (a, b) = (&keyframes[next - 1], &keyframes[next]);
+    let s = ((t - a.time) / (b.time - a.time).max(1e-6)).clamp(0.0, 1.0);
+    // The angle keeps counting over the loops, so that a mixer going from 0 to 2π spins smoothly
+    let angle = a.angle + (b.angle - a.angle) * s + cycles * (last.angle - first.angle);
+    (a.position.lerp(b.position, s), angle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn close(a: Vec2, b: Vec2) -> bool {
+        (a - b).length() < 1e-4
+    }
+
+    fn keyframe(time: f32, x: f32, angle: f32) -> Keyframe {
+        Keyframe { time, position: Vec2::new(x, 0.0), angle }
+    }
+
+    #[test]
+    fn keyframes_are_interpolated_and_looped() {
+        let keyframes = [keyframe(0.0, 0.0, 0.0), keyframe(1.0, 1.0, 0.0), keyframe(2.0, 0.0, 0.0)];
+        assert!(close(sample_keyframes(&keyframes, 0.25).0, Vec2::new(0.25, 0.0)));
+        assert!(close(sample_keyframes(&keyframes, 1.5).0, Vec2::new(0.5, 0.0)));
+        assert!(close(sample_keyframes(&keyframes, 2.25).0, Vec2::new(0.25, 0.0)));
+        // A single keyframe or none holds still
+        assert!(close(sample_keyframes(&keyframes[1..2], 0.5).0, Vec2::new(1.0, 0.0)));
+        assert_eq!(sample_keyframes(&[], 0.5), (Vec2::ZERO, 0.0));
+    }
+
+    #[test]
+    fn angle_keeps_counting_over_the_loops() {
+        let keyframes = [keyframe(0.0, 0.0, 0.0), keyframe(1.0, 0.0, 2.0 * PI)];
+        assert!((sample_keyframes(&keyframes, 0.5).1 - PI).abs() < 1e-4);
+        assert!((sample_keyframes(&keyframes, 1.5).1 - 3.0 * PI).abs() < 1e-4);
+    }
+
+    #[test]
+    fn keyframed_obstacle_reports_its_velocity() {
+        let keyframes = vec![keyframe(1.0, 1.0, 0.5), keyframe(0.0, 0.0, 0.0)];
+        let mut obstacle = Obstacle::new(Shape::Circle { radius: 0.1 }, Vec2::new(5.0, 5.0), 0.0).with_keyframes(keyframes);
+        // Sorted, and starting at the first keyframe
+        assert!(close(obstacle.position, Vec2::ZERO));
+        obstacle.advance(0.1);
+        assert!(close(obstacle.position, Vec2::new(0.1, 0.0)));
+        assert!(close(obstacle.velocity, Vec2::new(1.0, 0.0)));
+        assert!((obstacle.angle - 0.05).abs() < 1e-5);
+        assert!((obstacle.angular_velocity - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn set_transform_lands_on_the_target() {
+        let mut obstacle = Obstacle::new(Shape::Box { half_extents: Vec2::splat(0.1) }, Vec2::ZERO, 0.0);
+        obstacle.set_transform(Vec2::new(1.0, 0.0), 1.0, 0.2);
+        assert!(close(obstacle.velocity, Vec2::new(5.0, 0.0)));
+        obstacle.advance(0.15);
+        assert!(close(obstacle.position, Vec2::new(0.75, 0.0)));
+        obstacle.advance(0.15);
+        assert!(close(obstacle.position, Vec2::new(1.0, 0.0)));
+        assert_eq!((obstacle.velocity, obstacle.angle, obstacle.angular_velocity), (Vec2::ZERO, 1.0, 0.0));
+        obstacle.set_transform(Vec2::ZERO, 0.0, 0.0);
+        assert_eq!((obstacle.position, obstacle.angle), (Vec2::ZERO, 0.0));
+    }
+
+    #[test]
+    fn signed_distance_of_a_rotated_box() {
+        let obstacle = Obstacle::new(Shape::Box { half_extents: Vec2::new(0.2, 0.1) }, Vec2::new(1.0, 1.0), 0.5 * PI);
+        // The long side points up
+        let (distance, normal) = obstacle.signed_distance(Vec2::new(1.0, 1.5));
+        assert!((distance - 0.3).abs() < 1e-5 && close(normal, Vec2::Y), "{} {}", distance, normal);
+        let (distance, normal) = obstacle.signed_distance(Vec2::new(1.05, 1.0));
+        assert!((distance + 0.05).abs() < 1e-5 && close(normal, Vec2::X), "{} {}", distance, normal);
+    }
+
+    #[test]
+    fn collide_pushes_out_and_bounces() {
+        let obstacle = Obstacle::new(Shape::Circle { radius: 0.1 }, Vec2::ZERO, 0.0);
+        let mut position = Vec2::new(0.05, 0.0);
+        let mut velocity = Vec2::new(-1.0, 0.5);
+        obstacle.collide(&mut position, &mut velocity, 0.01, 0.5);
+        assert!(close(position, Vec2::new(0.11, 0.0)));
+        assert!(close(velocity, Vec2::new(0.5, 0.5)));
+
+        // Moving away or outside the margin is left alone
+        let mut velocity = Vec2::new(1.0, 0.0);
+        obstacle.collide(&mut position, &mut velocity, 0.01, 0.5);
+        assert_eq!(velocity, Vec2::new(1.0, 0.0));
+        let mut position = Vec2::new(0.0, 0.2);
+        let mut velocity = Vec2::new(0.0, -1.0);
+        obstacle.collide(&mut position, &mut velocity, 0.01, 0.5);
+        assert_eq!((position, velocity), (Vec2::new(0.0, 0.2), Vec2::new(0.0, -1.0)));
+    }
+
+    #[test]
+    fn collide_takes_over_the_obstacle_velocity() {
+        let mut obstacle = Obstacle::new(Shape::Circle { radius: 0.1 }, Vec2::ZERO, 0.0);
+        obstacle.velocity = Vec2::new(2.0, 0.0);
+        let mut position = Vec2::new(0.09, 0.0);
+        let mut velocity = Vec2::ZERO;
+        obstacle.collide(&mut position, &mut velocity, 0.0, 0.0);
+        assert!(close(velocity, Vec2::new(2.0, 0.0)), "{}", velocity);
+    }
+}
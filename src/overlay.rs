@@ -0,0 +1,127 @@
+use glam::Vec2;
+use rayon::prelude::*;
+
+use crate::solver::{FieldSampler, State};
+
+// Velocity field overlay: the SPH-interpolated velocity is sampled on a regular grid
+// and drawn as arrow glyphs or as streamlines. Plain line lists (pairs of points) in field coordinates.
+
+// An arrow shows the distance travelled in this time, at most one grid spacing
+const ARROW_TIME: f32 = 0.05;
+const ARROW_HEAD_SIZE: f32 = 0.35;
+const ARROW_HEAD_ANGLE: f32 = 0.45;
+// Integration step relative to the grid spacing, and the number of steps in each direction
+const STREAMLINE_STEP: f32 = 0.25;
+const STREAMLINE_STEPS: usize = 24;
+// Slower points count as stagnant, streamlines stop there
+const MIN_SPEED: f32 = 1e-3;
+
+pub fn arrows(state: &State, spacing: f32) -> Vec<Vec2> {
+    let sampler = state.sampler();
+    grid_points(state, spacing).par_iter().flat_map_iter(|&point|{
+        let mut segments = Vec::new();
+        if let Some(velocity) = sampler.velocity(point) {
+            let length = (velocity.length() * ARROW_TIME).min(0.9 * spacing);
+            if length > 0.05 * spacing {
+                let direction = velocity.normalize();
+                let (tail, tip) = (point - 0.5 * length * direction, point + 0.5 * length * direction);
+                let head = -ARROW_HEAD_SIZE * length * direction;
+                segments.extend_from_slice(&[
+                    tail, tip,
+                    tip, tip + Vec2::from_angle(ARROW_HEAD_ANGLE).rotate(head),
+                    tip, tip + Vec2::from_angle(-ARROW_HEAD_ANGLE).rotate(head),
+                ]);
+            }
+        }
+        segments
+    }).collect()
+}
+
+// One streamline through each grid point, traced forward and backward with the midpoint method
+pub fn streamlines(state: &State, spacing: f32) -> Vec<Vec2> {
+    let sampler = state.sampler();
+    let bounds = Vec2::new(state.field.width, state.field.height);
+    let step = STREAMLINE_STEP * spacing;
+    grid_points(state, spacing).par_iter().flat_map_iter(|&seed|{
+        let mut segments = Vec::new();
+        for sign in [1.0, -1.0] {
+            let mut point = seed;
+            for _ in 0..STREAMLINE_STEPS {
+                let Some(next) = advance(&sampler, point, sign * step) else { break };
+                if next.cmplt(Vec2::ZERO).any() || next.cmpgt(bounds).any() {
+                    break;
+                }
+                segments.extend_from_slice(&[point, next]);
+                point = next;
+            }
+        }
+        segments
+    }).collect()
+}
+
+// Moves `step` along the field direction, None outside the fluid or at stagnant points
+fn advance(sampler: &FieldSampler, point: Vec2, step: f32) -> Option<Vec2> {
+    let direction = |p: Vec2| sampler.velocity(p).filter(|v| v.length() > MIN_SPEED).map(Vec2::normalize);
+    let middle = point + 0.5 * step * direction(point)?;
+    Some(point + step * direction(middle)?)
+}
+
+// Centres of the grid cells
+fn grid_points(state: &State, spacing: f32) -> Vec<Vec2> {
+    let nx = (state.field.width / spacing) as usize;
+    let ny = (state.field.height / spacing) as usize;
+    (0..ny).flat_map(|j| (0..nx).map(move |i| (Vec2::new(i as f32, j as f32) + 0.5) * spacing)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::Scene;
+
+    const SPACING: f32 = 0.02;
+
+    // A block of fluid over the lower half of a 0.4 x 0.2 field, moving with `velocity`
+    fn state(velocity: Vec2) -> State {
+        let mut state = State::new(&Scene { num_particles: 2000, field_width: 0.4, field_height: 0.2, ..Default::default() });
+        state.particles.iter_mut().for_each(|particle| particle.velocity = velocity);
+        state
+    }
+
+    #[test]
+    fn uniform_field_gives_parallel_arrows() {
+        let velocity = Vec2::new(0.3, 0.4);
+        let segments = arrows(&state(velocity), SPACING);
+        assert!(!segments.is_empty());
+        // Shaft and two head strokes per arrow
+        assert_eq!(segments.len() % 6, 0);
+        for arrow in segments.chunks_exact(6) {
+            let shaft = arrow[1] - arrow[0];
+            assert!((shaft.normalize() - velocity.normalize()).length() < 1e-3, "{}", shaft);
+            assert!((shaft.length() - (velocity.length() * ARROW_TIME).min(0.9 * SPACING)).abs() < 1e-4, "{}", shaft);
+        }
+    }
+
+    #[test]
+    fn fluid_at_rest_has_no_arrows_or_streamlines() {
+        let state = state(Vec2::ZERO);
+        assert!(arrows(&state, SPACING).is_empty());
+        assert!(streamlines(&state, SPACING).is_empty());
+    }
+
+    #[test]
+    fn streamlines_stay_in_the_fluid() {
+        // Up and to the right, out through the surface
+        let state = state(Vec2::new(1.0, 1.0));
+        let sampler = state.sampler();
+        let segments = streamlines(&state, SPACING);
+        assert!(!segments.is_empty());
+        let bounds = Vec2::new(state.field.width, state.field.height);
+        assert!(segments.iter().all(|point| point.cmpge(Vec2::ZERO).all() && point.cmple(bounds).all()));
+        // Every step starts in the fluid and ends at most one step beyond it
+        let top = state.particles.iter().map(|particle| particle.position.y).fold(0.0, f32::max);
+        for segment in segments.chunks_exact(2) {
+            assert!(sampler.velocity(segment[0]).is_some(), "{}", segment[0]);
+            assert!(segment[1].y < top + 2.0 * STREAMLINE_STEP * SPACING + 0.01, "{}", segment[1]);
+        }
+    }
+}
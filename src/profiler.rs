@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+// Wall clock time of the solver phases, summed over the substeps of a frame
+// and kept for the last ROLLING_FRAMES frames.
+
+const ROLLING_FRAMES: usize = 120;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    RegisterCells, 
+    DensityPressure, 
+    Stress, 
+    Force, 
+    Tool, 
+    // Attractors and force fields of the grid solvers, SPH applies them in `Force`
+    External, 
+    // Moving the obstacles, and pushing the particles out of them for the grid solvers
+    Obstacles, 
+    Boundary, 
+    // Grid steps of the MPM and FLIP solvers
+    GridSolver, 
+    // Pushing overlapping FLIP particles apart
+    Separation, 
+    // The whole update, including the parts not measured on their own
+    Total, 
+}
+
+impl Phase {
+    pub const ALL: [Phase; 11] = [
+        Phase::RegisterCells, Phase::DensityPressure, Phase::Stress, Phase::Force, Phase::Tool,
+        Phase::External, Phase::Obstacles, Phase::Boundary, Phase::GridSolver, Phase::Separation, Phase::Total,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::RegisterCells => "register_cells", 
+            Phase::DensityPressure => "density_pressure", 
+            Phase::Stress => "stress", 
+            Phase::Force => "force", 
+            Phase::Tool => "tool", 
+            Phase::External => "external", 
+            Phase::Obstacles => "obstacles", 
+            Phase::Boundary => "boundary", 
+            Phase::GridSolver => "grid_solver", 
+            Phase::Separation => "separation", 
+            Phase::Total => "total", 
+        }
+    }
+}
+
+// Milliseconds per frame over the rolling window
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PhaseStats {
+    pub mean: f32, 
+    pub p50: f32, 
+    pub p95: f32, 
+    pub max: f32, 
+}
+
+#[derive(Clone, Debug)]
+pub struct Profiler {
+    // Sums of the frame in progress, in ms
+    current: [f32; Phase::ALL.len()], 
+    history: [VecDeque<f32>; Phase::ALL.len()], 
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler {
+            current: [0.0; Phase::ALL.len()],
+            history: std::array::from_fn(|_| VecDeque::with_capacity(ROLLING_FRAMES)),
+        }
+    }
+}
+
+impl Profiler {
+    pub fn add(&mut self, phase: Phase, duration: Duration) {
+        self.current[phase as usize] += duration.as_secs_f32() * 1000.0;
+    }
+
+    // Phases that didn't run in this frame count as zero
+    pub fn end_frame(&mut self) {
+        for (history, current) in self.history.iter_mut().zip(self.current.iter_mut()) {
+            if history.len() == ROLLING_FRAMES {
+                history.pop_front();
+            }
+            history.push_back(*current);
+            *current = 0.0;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Profiler::default();
+    }
+
+    pub fn stats(&self, phase: Phase) -> PhaseStats {
+        let mut times: Vec<f32> = self.history[phase as usize].iter().copied().collect();
+        if times.is_empty() {
+            return PhaseStats::default();
+        }
+        times.sort_unstable_by(f32::total_cmp);
+        let percentile = |p: f32| times[((times.len() - 1) as f32 * p).round() as usize];
+        PhaseStats {
+            mean: times.iter().sum::<f32>() / times.len() as f32,
+            p50: percentile(0.5),
+            p95: percentile(0.95),
+            max: times[times.len() - 1],
+        }
+    }
+}
+
+// One line per phase, for logs
+impl fmt::Display for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<18}{:>8}{:>8}{:>8}{:>8}  (ms per frame)", "phase", "mean", "p50", "p95", "max")?;
+        for phase in Phase::ALL {
+            let stats = self.stats(phase);
+            writeln!(f, "{:<18}{:>8.3}{:>8.3}{:>8.3}{:>8.3}", phase.name(), stats.mean, stats.p50, stats.p95, stats.max)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(value: u64) -> Duration {
+        Duration::from_millis(value)
+    }
+
+    #[test]
+    fn sums_the_substeps_of_a_frame() {
+        let mut profiler = Profiler::default();
+        profiler.add(Phase::Force, ms(2));
+        profiler.add(Phase::Force, ms(3));
+        profiler.end_frame();
+        let stats = profiler.stats(Phase::Force);
+        assert!((stats.max - 5.0).abs() < 1e-4, "{:?}", stats);
+        // Nothing recorded yet, or not run in the frame
+        assert_eq!(Profiler::default().stats(Phase::Force), PhaseStats::default());
+        assert_eq!(profiler.stats(Phase::Stress), PhaseStats::default());
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut profiler = Profiler::default();
+        // 1 to 100 ms in shuffled order
+        for i in 0..100 {
+            profiler.add(Phase::Total, ms((i * 37) % 100 + 1));
+            profiler.end_frame();
+        }
+        let stats = profiler.stats(Phase::Total);
+        assert!((stats.mean - 50.5).abs() < 1e-3, "{:?}", stats);
+        assert!((stats.p50 - 51.0).abs() < 1e-3, "{:?}", stats);
+        assert!((stats.p95 - 95.0).abs() < 1e-3, "{:?}", stats);
+        assert!((stats.max - 100.0).abs() < 1e-3, "{:?}", stats);
+    }
+
+    #[test]
+    fn keeps_the_last_frames() {
+        let mut profiler = Profiler::default();
+        for _ in 0..ROLLING_FRAMES {
+            profiler.add(Phase::Total, ms(100));
+            profiler.end_frame();
+        }
+        for _ in 0..ROLLING_FRAMES - 1 {
+            profiler.add(Phase::Total, ms(1));
+            profiler.end_frame();
+        }
+        // A single slow frame is left in the window
+        let stats = profiler.stats(Phase::Total);
+        assert!((stats.max - 100.0).abs() < 1e-3, "{:?}", stats);
+        assert!((stats.p95 - 1.0).abs() < 1e-3, "{:?}", stats);
+
+        profiler.add(Phase::Total, ms(1));
+        profiler.end_frame();
+        assert!((profiler.stats(Phase::Total).max - 1.0).abs() < 1e-3);
+
+        profiler.clear();
+        assert_eq!(profiler.stats(Phase::Total), PhaseStats::default());
+    }
+
+    #[test]
+    fn one_line_per_phase() {
+        let text = Profiler::default().to_string();
+        assert_eq!(text.lines().count(), Phase::ALL.len() + 1);
+        assert!(Phase::ALL.iter().all(|phase| text.contains(phase.name())));
+    }
+}
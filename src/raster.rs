@@ -0,0 +1,151 @@
+use std::io::Write;
+
+use glam::Vec2;
+
+use crate::colormap::{ColorMap, ColorMode, ParticleColors, BACKGROUND_COLOR, OBSTACLE_COLOR};
+use crate::solver::{State, PARTICLE_SIZE};
+
+// Software renderer for headless use (CI, thumbnails, regression images).
+// Draws the same view as the WebGL point shader: opaque discs on the background,
+// particles first and obstacles on top, the field height fills the image height.
+
+// Disc radius relative to the particle size, as in the vertex shader
+const DISC_SCALE: f32 = 0.8;
+
+// 8 bit RGBA, rows from top to bottom
+pub struct Image {
+    pub width: u32, 
+    pub height: u32, 
+    pub pixels: Vec<u8>, 
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32, color: (f32, f32, f32)) -> Self {
+        let pixels = to_rgba8(color).repeat((width * height) as usize);
+        Image { width, height, pixels }
+    }
+
+    pub fn write_png<W: Write>(&self, writer: W) -> Result<(), png::EncodingError> {
+        let mut encoder = png::Encoder::new(writer, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&self.pixels)
+    }
+
+    pub fn save_png<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), png::EncodingError> {
+        let file = std::fs::File::create(path)?;
+        self.write_png(std::io::BufWriter::new(file))
+    }
+
+    // Disc in pixel coordinates with y up, like the canvas in the shaders
+    fn fill_disc(&mut self, center: Vec2, radius: f32, color: [u8; 4]) {
+        let x0 = (center.x - radius).floor().max(0.0) as u32;
+        let x1 = ((center.x + radius).ceil().max(0.0) as u32).min(self.width);
+        let y0 = (center.y - radius).floor().max(0.0) as u32;
+        let y1 = ((center.y + radius).ceil().max(0.0) as u32).min(self.height);
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let pixel = Vec2::new(x as f32 + 0.5, y as f32 + 0.5);
+                if (pixel - center).length_squared() < radius * radius {
+                    let row = self.height - 1 - y;
+                    let offset = ((row * self.width + x) * 4) as usize;
+                    self.pixels[offset..offset + 4].copy_from_slice(&color);
+                }
+            }
+        }
+    }
+}
+
+pub fn render(state: &State, width: u32, height: u32, color_mode: ColorMode, color_map: ColorMap) -> Image {
+    let mut image = Image::new(width, height, BACKGROUND_COLOR);
+    let scale = height as f32 / state.field.height;
+
+    let colors = ParticleColors::new(state, color_mode, color_map);
+    for (i, particle) in state.particles.iter().enumerate() {
+        let color = to_rgba8(colors.color(i, particle));
+        image.fill_disc(particle.position * scale, DISC_SCALE * particle.size * scale, color);
+    }
+
+    let obstacle_color = to_rgba8(OBSTACLE_COLOR);
+    for obstacle in &state.obstacles {
+        for point in obstacle.sample_points(PARTICLE_SIZE).iter() {
+            image.fill_disc(*point * scale, DISC_SCALE * PARTICLE_SIZE * scale, obstacle_color);
+        }
+    }
+    image
+}
+
+fn to_rgba8(color: (f32, f32, f32)) -> [u8; 4] {
+    let channel = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    [channel(color.0), channel(color.1), channel(color.2), 255]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::obstacle::{Obstacle, Shape};
+    use crate::solver::Scene;
+
+    // A single resting particle in a 0.4 x 0.2 field
+    fn state() -> State {
+        let mut state = State::new(&Scene { num_particles: 1, field_width: 0.4, field_height: 0.2, ..Default::default() });
+        state.particles[0].position = Vec2::new(0.1, 0.05);
+        state.particles[0].velocity = Vec2::ZERO;
+        state
+    }
+
+    fn pixel(image: &Image, x: u32, row: u32) -> [u8; 4] {
+        let offset = ((row * image.width + x) * 4) as usize;
+        let p = &image.pixels[offset..offset + 4];
+        [p[0], p[1], p[2], p[3]]
+    }
+
+    #[test]
+    fn image_has_requested_size() {
+        let image = Image::new(7, 3, BACKGROUND_COLOR);
+        assert_eq!(image.pixels.len(), 7 * 3 * 4);
+        assert!(image.pixels.chunks_exact(4).all(|p| p == to_rgba8(BACKGROUND_COLOR)));
+
+        let image = render(&state(), 40, 20, ColorMode::Dye, ColorMap::Rainbow);
+        assert_eq!((image.width, image.height, image.pixels.len()), (40, 20, 40 * 20 * 4));
+    }
+
+    #[test]
+    fn zero_size_renders_empty() {
+        let image = render(&state(), 0, 0, ColorMode::Dye, ColorMap::Rainbow);
+        assert!(image.pixels.is_empty());
+        let image = render(&state(), 16, 0, ColorMode::Dye, ColorMap::Rainbow);
+        assert!(image.pixels.is_empty());
+    }
+
+    #[test]
+    fn particle_is_drawn_with_y_up() {
+        // 1000 pixels per unit, the particle lands at pixel (100, 50) counted from the bottom
+        let image = render(&state(), 400, 200, ColorMode::Dye, ColorMap::Rainbow);
+        let background = to_rgba8(BACKGROUND_COLOR);
+        assert_ne!(pixel(&image, 100, 200 - 1 - 50), background);
+        assert_eq!(pixel(&image, 100, 50), background);
+        assert_eq!(pixel(&image, 300, 200 - 1 - 50), background);
+    }
+
+    #[test]
+    fn obstacles_are_drawn_over_particles() {
+        let mut state = state();
+        state.obstacles.push(Obstacle::new(Shape::Circle { radius: 0.03 }, Vec2::new(0.1, 0.05), 0.0));
+        let image = render(&state, 40, 20, ColorMode::Dye, ColorMap::Rainbow);
+        assert_eq!(pixel(&image, 10, 20 - 1 - 5), to_rgba8(OBSTACLE_COLOR));
+    }
+
+    #[test]
+    fn png_round_trips() {
+        let image = render(&state(), 40, 20, ColorMode::Dye, ColorMap::Rainbow);
+        let mut bytes = Vec::new();
+        image.write_png(&mut bytes).unwrap();
+
+        let mut reader = png::Decoder::new(std::io::Cursor::new(bytes)).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (40, 20));
+        assert_eq!(&pixels[..info.buffer_size()], &image.pixels[..]);
+    }
+}
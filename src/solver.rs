@@ -1,23 +1,151 @@
-use core::num;
-
-use glam::Vec2;
+use glam::{Mat2, Vec2};
 // use rand::Rng;
 use rand::rngs::StdRng;
 use rand::{SeedableRng, Rng};
 use web_time::Instant;
-use std::sync::atomic::{AtomicU32, Ordering};
-use crate::MouseInfo;
+
+use crate::mpm::{Mpm, MPM_DT, MPM_STEPS};
+use crate::flip::{Flip, FLIP_DT, FLIP_STEPS};
+use crate::force_field::ForceField;
+use crate::obstacle::Obstacle;
+use crate::id_list::IdList;
+use crate::profiler::{Phase, Profiler};
 
 use rayon::prelude::*;
 use wasm_bindgen::prelude::*;
 use std::f32::consts::PI;
+use std::fmt;
 
 
 pub struct State {
     pub particles: Vec<Particle>, 
     neighbors: Vec<Vec<Neighbor>>, 
+    // The densities and neighbour lists belong to the current particles, after an SPH substep
+    sph_current: bool, 
     pub field: Field, 
     cells: Cells, 
+    // Positions and displacements of `separate_particles`, kept to avoid allocating every substep
+    positions: Vec<Vec2>, 
+    separation: Vec<Vec2>, 
+    pub dye_diffusion: f32, 
+    pub solver_kind: SolverKind, 
+    pub gravity: Vec2, 
+    pub attractors: IdList<Attractor>, 
+    force_fields: IdList<Box<dyn ForceField>>, 
+    pub obstacles: IdList<Obstacle>, 
+    pub tool: Tool, 
+    rng: StdRng, 
+    // Simulated time in seconds, drives the time dependent force fields
+    time: f32, 
+    // Simulated time per frame relative to normal speed, realised by running more or fewer substeps
+    time_scale: f32, 
+    // Fraction of a substep left over from the previous frames
+    step_carry: f32, 
+    // The next frame runs at least one substep
+    step_requested: bool, 
+    mpm: Mpm, 
+    flip: Flip, 
+    profiler: Profiler, 
+}
+
+// Point gravity placed in the scene. A negative strength repels.
+#[derive(Clone, Copy, Debug)]
+pub struct Attractor {
+    pub position: Vec2, 
+    // Acceleration near the centre, falls off with the inverse square outside `radius`
+    pub strength: f32, 
+    pub radius: f32, 
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SolverKind {
+    Sph, 
+    Mpm, 
+    // FLIP/PIC on a MAC grid, every material behaves as liquid
+    Flip, 
+}
+
+impl SolverKind {
+    // Materials without a model in the solver run as liquid
+    pub fn supports(self, material: Material) -> bool {
+        match self {
+            SolverKind::Sph => matches!(material, Material::Liquid | Material::Sand), 
+            SolverKind::Mpm => true, 
+            SolverKind::Flip => material == Material::Liquid, 
+        }
+    }
+}
+
+// What the pointer does to the particles around it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToolMode {
+    Attract, 
+    Repel, 
+    Swirl, 
+    // Particles take over the velocity of the pointer, like stirring with a spoon
+    Drag, 
+    Add, 
+    Delete, 
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Tool {
+    pub mode: ToolMode, 
+    // Fraction of the field height
+    pub radius: f32, 
+    // Acceleration (m/s^2) at the centre. `Drag` scales its rate by the ratio to the default strength.
+    pub strength: f32, 
+}
+
+// Summary of the state after a step, for logging and monitoring.
+// Every particle has the mass MASS, energies and momentum are in the units of the solver.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Diagnostics {
+    // Simulated seconds
+    pub time: f32, 
+    pub particle_count: usize, 
+    pub kinetic_energy: f32, 
+    // In the gravity only (attractors and force fields are left out), zero at the origin
+    pub potential_energy: f32, 
+    pub momentum: Vec2, 
+    // Compression relative to TARGET_DENSITY. Lower densities don't count, particles at the surface
+    // always have fewer neighbours.
+    pub mean_density_error: f32, 
+    pub max_density_error: f32, 
+    pub max_speed: f32, 
+    // Neighbours within the kernel radius
+    pub mean_neighbors: f32, 
+    pub min_neighbors: u32, 
+    pub max_neighbors: u32, 
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "t={:.3}s n={} ke={:.4} pe={:.4} p=({:.4}, {:.4}) density error mean={:.2}% max={:.2}% max speed={:.3} neighbors {}/{:.1}/{}",
+            self.time, self.particle_count, self.kinetic_energy, self.potential_energy, self.momentum.x, self.momentum.y,
+            100.0 * self.mean_density_error, 100.0 * self.max_density_error, self.max_speed,
+            self.min_neighbors, self.mean_neighbors, self.max_neighbors)
+    }
+}
+
+// Per-particle values for visualization. Density, pressure, near pressure and force are computed
+// by the SPH solver only, the grid solvers leave the values of the last SPH step.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParticleQuantity {
+    Speed, 
+    Density, 
+    Pressure, 
+    NearPressure, 
+    Force, 
+    // Out-of-plane component of the curl of the velocity (1/s), counterclockwise is positive
+    Vorticity, 
+}
+
+// An active pointer contact in field coordinates, velocity in m/s of simulated time
+#[derive(Clone, Copy, Debug)]
+pub struct Pointer {
+    pub position: Vec2, 
+    pub velocity: Vec2, 
 }
 
 #[derive(Clone)]
@@ -26,6 +154,16 @@ struct Neighbor{
     j: u32
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Material {
+    Liquid, 
+    // Granular material: no cohesion, Drucker-Prager friction between grains (SPH and MPM)
+    Sand, 
+    // Elastic and plastic solids, only supported by the MPM solver
+    Jelly, 
+    Snow, 
+}
+
 #[derive(Clone)]
 pub struct Particle {
     pub position: Vec2, 
@@ -36,6 +174,24 @@ pub struct Particle {
     near_pressure: f32, 
     near_density: f32,
     pub size: f32, 
+    pub dye: f32, 
+    dye_rate: f32, 
+    pub material: Material, 
+    // Deviatoric (shear) stress of sand grains
+    stress: Mat2, 
+}
+
+// Initial setup of the tank. The field size doesn't depend on the particle count.
+#[derive(Clone, Copy, Debug)]
+pub struct Scene {
+    pub num_particles: u32, 
+    pub field_width: f32, 
+    pub field_height: f32, 
+    // Height of the initial block as a fraction of the field height, its width follows from the particle count
+    pub fill_level: f32, 
+    // The last `top_fraction` of the particles (the top of the initial block) are `top_material`, the rest is liquid
+    pub top_material: Material, 
+    pub top_fraction: f32, 
 }
 
 pub struct Field {
@@ -43,6 +199,28 @@ pub struct Field {
     pub width: f32,
 }
 
+impl Default for Scene {
+    // The dam break of the demo, all liquid
+    fn default() -> Self {
+        Scene {
+            num_particles: DEFAULT_PARTICLE_COUNT,
+            field_width: DEFAULT_FIELD_HEIGHT * DEFAULT_ASPECT,
+            field_height: DEFAULT_FIELD_HEIGHT,
+            fill_level: DEFAULT_FILL_LEVEL,
+            top_material: Material::Liquid,
+            top_fraction: 0.0,
+        }
+    }
+}
+
+impl Scene {
+    // NaN, from the aspect ratio of an empty canvas, becomes the smallest size and infinity the largest
+    fn field(&self) -> Field {
+        let size = |size: f32| if size.is_nan() { MIN_FIELD_SIZE } else { size.clamp(MIN_FIELD_SIZE, MAX_FIELD_SIZE) };
+        Field { height: size(self.field_height), width: size(self.field_width) }
+    }
+}
+
 pub struct Cells {
     pub cells: Vec<Vec<u32>>, 
     pub nx: usize, 
@@ -53,11 +231,20 @@ const DT: f32 = 0.0010;
 pub const PARTICLE_SIZE: f32 = 0.005;
 const KERNEL_RADIUS: f32 = 2.0 * PARTICLE_SIZE;
 const MOUSE_FORCE_STRENGTH: f32 = 200.0;
+// Twice the old constant pull to make up for the falloff
+const DEFAULT_TOOL_STRENGTH: f32 = 2.0 * MOUSE_FORCE_STRENGTH / TARGET_DENSITY;
+const DEFAULT_TOOL_RADIUS: f32 = 0.2;
+// Rate (1/s) at which `Drag` matches the pointer velocity at the default strength
+const DRAG_RATE: f32 = 40.0;
+// Particles spawned per frame by the `Add` tool
+const ADD_RATE: usize = 20;
 const KERNEL_RADIUS_SQ: f32 = KERNEL_RADIUS * KERNEL_RADIUS;
 const KERNEL_RADIUS_POW4: f32 = KERNEL_RADIUS_SQ * KERNEL_RADIUS_SQ;
 const KERNEL_RADIUS_POW5: f32 = KERNEL_RADIUS_POW4 * KERNEL_RADIUS;
 const KERNEL_RADIUS_POW8: f32 = KERNEL_RADIUS_POW4 * KERNEL_RADIUS_POW4;
 const TARGET_DENSITY: f32 = 9.0;
+// Below this interpolated density a point counts as outside the fluid
+const EMPTY_DENSITY: f32 = 0.2 * TARGET_DENSITY;
 const STIFFNESS: f32 = 0.008;
 const NEAR_STIFFNESS: f32 = 8e-5;
 const MASS: f32 = 1.0;
@@ -67,9 +254,32 @@ const SPIKY_POW2_GRAD: f32 = 12.0 / (PI * KERNEL_RADIUS_POW4);
 const SPIKY_POW3_GRAD: f32 = 30.0 / (PI * KERNEL_RADIUS_POW5); 
 const VISC_LAP: f32 = 4.0 / (PI * KERNEL_RADIUS_POW8); 
 const VISCOSITY: f32 = 0.3;
+const DYE_DIFFUSION: f32 = 0.05;
+// Drucker-Prager friction coefficient and shear modulus of sand. The friction coefficient also applies
+// between grains and on the walls.
+const SAND_FRICTION: f32 = 0.8;
+const SAND_SHEAR_MODULUS: f32 = 0.05;
+// Grains are heavier than water: the same interparticle forces accelerate them less
+const SAND_DENSITY_RATIO: f32 = 1.6;
+// Fraction of the normal velocity bouncing back from walls and obstacles
+const RESTITUTION: f32 = 0.3;
 const EPS: f32 = 1e-30;
-const GRV: Vec2 = Vec2::new(0.0, -9.8);
+pub const GRV: Vec2 = Vec2::new(0.0, -9.8);
 const SOLVER_STEPS: u32 = 10;
+// FLIP substeps per pass of `separate_particles`
+const SEPARATION_INTERVAL: u32 = 2;
+pub const MIN_TIME_SCALE: f32 = 1.0 / 32.0;
+pub const MAX_TIME_SCALE: f32 = 4.0;
+pub const DEFAULT_PARTICLE_COUNT: u32 = 10000;
+pub const DEFAULT_FIELD_HEIGHT: f32 = 0.8;
+// Width over height of the default scene, that of a 16:9 canvas
+const DEFAULT_ASPECT: f32 = 16.0 / 9.0;
+// Smallest field with room for a cell between the wall margins
+const MIN_FIELD_SIZE: f32 = 4.0 * KERNEL_RADIUS;
+// Larger fields and counts are clamped, the cell grids and the particles are allocated up front
+pub const MAX_FIELD_SIZE: f32 = 10.0;
+pub const MAX_PARTICLES: u32 = 200_000;
+pub const DEFAULT_FILL_LEVEL: f32 = 0.5;
 
 #[wasm_bindgen]
 extern "C" {
@@ -83,56 +293,473 @@ macro_rules! benchmark {
     ($code:block) => {{
         let start = Instant::now(); 
         $code
-        start.elapsed().as_micros()
+        start.elapsed()
     }};
 }
 
 impl State {
-    pub fn new(num_particles: u32, aspect_ratio: f32) -> Self {
+    pub fn new(scene: &Scene) -> Self {
         let neighbors = Vec::new();
+        let sph_current = false;
         let particles = Vec::new();
-        let height = Self::height_from_num_particles(num_particles);
-        let width = height * aspect_ratio;
-        let cells = Cells::new(height, width, KERNEL_RADIUS);
-        let field = Field { height, width };
+        let field = scene.field();
+        let cells = Cells::new(field.height, field.width, KERNEL_RADIUS);
+        let positions = Vec::new();
+        let separation = Vec::new();
+
+        let dye_diffusion = DYE_DIFFUSION;
+        let solver_kind = SolverKind::Sph;
+        let gravity = GRV;
+        let attractors = IdList::new();
+        let force_fields = IdList::new();
+        let obstacles = IdList::new();
+        let time = 0.0;
+        let time_scale = 1.0;
+        let step_carry = 0.0;
+        let step_requested = false;
+        let tool = Tool { mode: ToolMode::Attract, radius: DEFAULT_TOOL_RADIUS, strength: DEFAULT_TOOL_STRENGTH };
+        let rng = StdRng::seed_from_u64(54321);
+        let mpm = Mpm::new(&field, KERNEL_RADIUS);
+        let flip = Flip::new(&field, KERNEL_RADIUS);
+        let profiler = Profiler::default();
 
-        let mut state = Self { particles, neighbors, field, cells };
+        let mut state = Self { particles, neighbors, sph_current, field, cells, positions, separation, dye_diffusion, solver_kind, gravity, attractors, force_fields, obstacles, tool, rng, time, time_scale, step_carry, step_requested, mpm, flip, profiler };
 
-        state.init_particles(num_particles, aspect_ratio);
+        state.init_particles(scene);
 
         state
     }
 
-    pub fn update(&mut self, mouse_position: Vec2, mouse_dragging: bool) {
-        for _ in 0..SOLVER_STEPS {
+    // Every active pointer applies the tool on its own.
+    // Returns no diagnostics: they need a neighbour search of their own for the grid solvers,
+    // so callers that want them call `diagnostics` after the update.
+    pub fn update(&mut self, pointers: &[Pointer]) {
+        let start = Instant::now();
+        self.advance(pointers);
+        self.profiler.add(Phase::Total, start.elapsed());
+        self.profiler.end_frame();
+    }
+
+    // Timings of the recent frames
+    pub fn profiler(&self) -> &Profiler {
+        &self.profiler
+    }
+
+    fn advance(&mut self, pointers: &[Pointer]) {
+        for pointer in pointers {
+            self.edit_particles(*pointer);
+        }
+
+        if self.solver_kind != SolverKind::Sph {
+            self.sph_current = false;
+        }
+        if self.solver_kind == SolverKind::Mpm {
+            for _ in 0..self.substeps(MPM_STEPS) {
+                self.grid_impulses(pointers, MPM_DT);
+                let t = benchmark!({self.mpm.step(&mut self.particles, &self.field, self.gravity, MPM_DT)});
+                self.profiler.add(Phase::GridSolver, t);
+                let t = benchmark!({self.collide_obstacles()});
+                self.profiler.add(Phase::Obstacles, t);
+                self.time += MPM_DT;
+            }
+            return;
+        }
+        if self.solver_kind == SolverKind::Flip {
+            for i in 0..self.substeps(FLIP_STEPS) {
+                self.grid_impulses(pointers, FLIP_DT);
+                let t = benchmark!({self.flip.step(&mut self.particles, &self.field, self.gravity, FLIP_DT)});
+                self.profiler.add(Phase::GridSolver, t);
+                // The drift compensation of the grid solver keeps the density, the separation only has to
+                // catch the pairs that it misses
+                if i % SEPARATION_INTERVAL == 0 {
+                    let t = benchmark!({self.separate_particles()});
+                    self.profiler.add(Phase::Separation, t);
+                }
+                let t = benchmark!({self.collide_obstacles()});
+                self.profiler.add(Phase::Obstacles, t);
+                self.time += FLIP_DT;
+            }
+            return;
+        }
+
+        for _ in 0..self.substeps(SOLVER_STEPS) {
             let t1 = benchmark!({self.cells.register_cells(&self.particles)});
             let t2 = benchmark!({self.compute_density_pressure()});
-            let t3 = benchmark!({self.compute_force()});
-            let t4 = if mouse_dragging { benchmark!({self.mouse_force(mouse_position)}) } else { 0 };
+            let t3 = benchmark!({self.update_stress()});
+            let t4 = benchmark!({
+                self.compute_force();
+                self.sand_friction();
+            });
+            if !pointers.is_empty() {
+                let t = benchmark!({self.tool_force(pointers)});
+                self.profiler.add(Phase::Tool, t);
+            }
+            let t = benchmark!({self.move_obstacles(DT)});
+            self.profiler.add(Phase::Obstacles, t);
             let t5 = benchmark!({self.handle_boundary()});
-            let s = format!("{}us, {}us, {}us, {}us, {}us", t1, t2, t3, t4, t5);
-            // log(&s);
+            self.time += DT;
+            for (phase, t) in [(Phase::RegisterCells, t1), (Phase::DensityPressure, t2), (Phase::Stress, t3), (Phase::Force, t4), (Phase::Boundary, t5)] {
+                self.profiler.add(phase, t);
+            }
+            self.sph_current = true;
+        }
+    }
+
+    // Velocity changes of the grid solvers before their step, timed on their own
+    fn grid_impulses(&mut self, pointers: &[Pointer], dt: f32) {
+        let t1 = benchmark!({self.tool_impulse(pointers, dt)});
+        let t2 = benchmark!({self.external_impulse(dt)});
+        let t3 = benchmark!({self.move_obstacles(dt)});
+        for (phase, t) in [(Phase::Tool, t1), (Phase::External, t2), (Phase::Obstacles, t3)] {
+            self.profiler.add(phase, t);
+        }
+    }
+
+    // Simulated seconds per call to `update`, the same for every solver
+    pub fn frame_time(&self) -> f32 {
+        let frame_time = match self.solver_kind {
+            SolverKind::Sph => SOLVER_STEPS as f32 * DT, 
+            SolverKind::Mpm => MPM_STEPS as f32 * MPM_DT, 
+            SolverKind::Flip => FLIP_STEPS as f32 * FLIP_DT, 
+        };
+        frame_time * self.time_scale
+    }
+
+    pub fn particle_quantity(&self, quantity: ParticleQuantity) -> Vec<f32> {
+        let mut values = Vec::new();
+        self.particle_quantity_into(quantity, &mut values);
+        values
+    }
+
+    // Like `particle_quantity`, overwriting `values` so that a buffer kept over the frames is reused
+    pub fn particle_quantity_into(&self, quantity: ParticleQuantity, values: &mut Vec<f32>) {
+        values.clear();
+        match quantity {
+            ParticleQuantity::Speed => values.par_extend(self.particles.par_iter().map(|p| p.velocity.length())), 
+            ParticleQuantity::Density => values.par_extend(self.particles.par_iter().map(|p| p.density)), 
+            ParticleQuantity::Pressure => values.par_extend(self.particles.par_iter().map(|p| p.pressure)), 
+            ParticleQuantity::NearPressure => values.par_extend(self.particles.par_iter().map(|p| p.near_pressure)), 
+            ParticleQuantity::Force => values.par_extend(self.particles.par_iter().map(|p| p.force.length())), 
+            ParticleQuantity::Vorticity => self.vorticity(values), 
+        }
+    }
+
+    // Weighted least squares fit of a rigid rotation to the relative velocities of the neighbours.
+    // Doesn't depend on the density, so it works the same for every solver.
+    fn vorticity(&self, values: &mut Vec<f32>) {
+        let mut cells = Cells::new(self.field.height, self.field.width, KERNEL_RADIUS);
+        cells.register_cells(&self.particles);
+        let particles = &self.particles;
+        values.par_extend(particles.par_iter().map(|pi|{
+            let mut curl = 0.0;
+            let mut norm = 0.0;
+            for j in cells.neighbors(pi, KERNEL_RADIUS) {
+                let pj = &particles[j as usize];
+                let rij = pj.position - pi.position;
+                let r2 = rij.length_squared();
+                if !(EPS..KERNEL_RADIUS_SQ).contains(&r2) {
+                    continue;
+                }
+                let w = (1.0 - r2.sqrt() / KERNEL_RADIUS).powi(2);
+                curl += w * rij.perp_dot(pj.velocity - pi.velocity);
+                norm += w * r2;
+            }
+            // A rotation with angular velocity Ω has vorticity 2Ω
+            if norm > 0.0 { 2.0 * curl / norm } else { 0.0 }
+        }));
+    }
+
+    // Densities and neighbour counts are computed with the SPH kernel for every solver,
+    // so that the grid solvers can be compared with SPH. Computed on demand, for the grid solvers
+    // this costs a neighbour search of its own.
+    pub fn diagnostics(&self) -> Diagnostics {
+        let particles = &self.particles;
+        let densities = self.densities_and_neighbors();
+
+        let mut diagnostics = Diagnostics {
+            time: self.time,
+            particle_count: particles.len(),
+            min_neighbors: if particles.is_empty() { 0 } else { u32::MAX },
+            ..Default::default()
+        };
+        let mut total_error = 0.0;
+        let mut total_neighbors = 0;
+        for (particle, (density, count)) in particles.iter().zip(densities) {
+            let speed2 = particle.velocity.length_squared();
+            diagnostics.kinetic_energy += 0.5 * MASS * speed2;
+            diagnostics.potential_energy -= MASS * self.gravity.dot(particle.position);
+            diagnostics.momentum += MASS * particle.velocity;
+            diagnostics.max_speed = diagnostics.max_speed.max(speed2);
+            let error = (density / TARGET_DENSITY - 1.0).max(0.0);
+            total_error += error;
+            diagnostics.max_density_error = diagnostics.max_density_error.max(error);
+            total_neighbors += count;
+            diagnostics.min_neighbors = diagnostics.min_neighbors.min(count);
+            diagnostics.max_neighbors = diagnostics.max_neighbors.max(count);
+        }
+        diagnostics.max_speed = diagnostics.max_speed.sqrt();
+        if !particles.is_empty() {
+            diagnostics.mean_density_error = total_error / particles.len() as f32;
+            diagnostics.mean_neighbors = total_neighbors as f32 / particles.len() as f32;
+        }
+        diagnostics
+    }
+
+    // The SPH solver has them from its last substep, the positions have moved by one substep since.
+    // Otherwise they need a pass of their own.
+    fn densities_and_neighbors(&self) -> Vec<(f32, u32)> {
+        if self.sph_current {
+            return self.particles.par_iter().zip_eq(self.neighbors.par_iter())
+                .map(|(particle, neighbors)| (particle.density, neighbors.len() as u32))
+                .collect();
         }
-        // log(&s);
-        // println!("{}", s);
-        // self.cells.register_cells(&self.particles);
-        // self.compute_density_pressure();
-        // self.compute_force();
-        // self.handle_boundary();
+        let mut cells = Cells::new(self.field.height, self.field.width, KERNEL_RADIUS);
+        cells.register_cells(&self.particles);
+        let particles = &self.particles;
+        particles.par_iter().map(|pi|{
+            let mut density = 0.0;
+            let mut count = 0;
+            for j in cells.neighbors(pi, KERNEL_RADIUS) {
+                let r2 = (particles[j as usize].position - pi.position).length_squared();
+                if r2 < KERNEL_RADIUS_SQ {
+                    let a = KERNEL_RADIUS_SQ - r2;
+                    density += MASS * SPIKY_POW2 * a * a;
+                    if EPS * EPS < r2 {
+                        count += 1;
+                    }
+                }
+            }
+            (density, count)
+        }).collect()
+    }
+
+    // Interpolation at arbitrary points, for the current particle positions
+    pub fn sampler(&self) -> FieldSampler<'_> {
+        let mut cells = Cells::new(self.field.height, self.field.width, KERNEL_RADIUS);
+        cells.register_cells(&self.particles);
+        FieldSampler { particles: &self.particles, cells }
+    }
+
+    // Builds the neighbour grid on every call, use `sampler` for many points
+    pub fn sample_point(&self, point: Vec2) -> FieldSample {
+        self.sampler().sample(point)
+    }
+
+    pub fn sample_grid(&self, nx: usize, ny: usize) -> FieldGrid {
+        let sampler = self.sampler();
+        let cell = Vec2::new(self.field.width / nx as f32, self.field.height / ny as f32);
+        let samples: Vec<FieldSample> = (0..nx * ny).into_par_iter().map(|k|{
+            let point = (Vec2::new((k % nx) as f32, (k / nx) as f32) + 0.5) * cell;
+            sampler.sample(point)
+        }).collect();
+        FieldGrid {
+            nx,
+            ny,
+            density: samples.iter().map(|sample| sample.density).collect(),
+            velocity: samples.iter().flat_map(|sample| sample.velocity.to_array()).collect(),
+            pressure: samples.iter().map(|sample| sample.pressure).collect(),
+        }
+    }
+
+    pub fn time_scale(&self) -> f32 {
+        self.time_scale
+    }
+
+    // The substep size stays fixed for stability, so fast forward costs proportionally more time per frame.
+    // Non-finite values are ignored.
+    pub fn set_time_scale(&mut self, time_scale: f32) {
+        if time_scale.is_finite() {
+            self.time_scale = time_scale.clamp(MIN_TIME_SCALE, MAX_TIME_SCALE);
+        }
+    }
+
+    // Like `update`, but advances at least one substep also in slow motion, for stepping a paused simulation
+    pub fn single_step(&mut self, pointers: &[Pointer]) {
+        self.step_requested = true;
+        self.update(pointers)
+    }
+
+    // Number of substeps for this frame. Slow motion runs a substep only every few frames.
+    fn substeps(&mut self, steps: u32) -> u32 {
+        self.step_carry += steps as f32 * self.time_scale;
+        let mut n = self.step_carry.floor();
+        if self.step_requested {
+            n = n.max(1.0);
+            self.step_requested = false;
+        }
+        // A forced substep doesn't delay the following ones
+        self.step_carry = (self.step_carry - n).max(0.0);
+        n as u32
+    }
+
+    fn tool_radius(&self) -> f32 {
+        self.tool.radius * self.field.height
+    }
+
+    fn tool_force(&mut self, pointers: &[Pointer]) {
+        let tool = self.tool;
+        let radius = self.tool_radius();
+        self.particles.par_iter_mut().for_each(|particle|{
+            for pointer in pointers {
+                particle.force += particle.density * tool_acceleration(tool, radius, *pointer, particle);
+            }
+        });
+    }
+
+    // Same as `tool_force`, applied directly to the velocities for solvers that don't use `Particle::force`
+    fn tool_impulse(&mut self, pointers: &[Pointer], dt: f32) {
+        if pointers.is_empty() {
+            return;
+        }
+        let tool = self.tool;
+        let radius = self.tool_radius();
+        self.particles.par_iter_mut().for_each(|particle|{
+            for pointer in pointers {
+                particle.velocity += tool_acceleration(tool, radius, *pointer, particle) * dt;
+            }
+        });
+    }
+
+    // Once per frame, the `Add` and `Delete` tools change the particle set
+    fn edit_particles(&mut self, pointer: Pointer) {
+        let radius = self.tool_radius();
+        match self.tool.mode {
+            ToolMode::Add => self.spawn_particles(pointer, radius),
+            ToolMode::Delete => self.remove_particles(|particle| (particle.position - pointer.position).length() < radius),
+            _ => {}
+        }
+    }
+
+    // Drops liquid at random free spots around the pointer, moving with it
+    fn spawn_particles(&mut self, pointer: Pointer, radius: f32) {
+        let min = Vec2::splat(KERNEL_RADIUS);
+        let max = Vec2::new(self.field.width, self.field.height) - KERNEL_RADIUS;
+        let mut occupied: Vec<Vec2> = self.particles.iter()
+            .map(|particle| particle.position)
+            .filter(|position| (*position - pointer.position).length() < radius + PARTICLE_SIZE)
+            .collect();
+
+        let mut added = 0;
+        for _ in 0..4 * ADD_RATE {
+            let offset = Vec2::from_angle(2.0 * PI * self.rng.gen::<f32>()) * radius * self.rng.gen::<f32>().sqrt();
+            let position = pointer.position + offset;
+            let outside = position.cmplt(min).any() || position.cmpgt(max).any();
+            if outside || occupied.iter().any(|other| (*other - position).length() < PARTICLE_SIZE) {
+                continue;
+            }
+            self.add_particle(position, 1.0, Material::Liquid);
+            self.particles.last_mut().unwrap().velocity = pointer.velocity;
+            occupied.push(position);
+            added += 1;
+            if added == ADD_RATE {
+                break;
+            }
+        }
+    }
+
+    fn remove_particles<F>(&mut self, remove: F)
+    where F: Fn(&Particle) -> bool
+    {
+        let keep: Vec<bool> = self.particles.iter().map(|particle| !remove(particle)).collect();
+        let mut keep_iter = keep.iter();
+        self.particles.retain(|_| *keep_iter.next().unwrap());
+        let mut keep_iter = keep.iter();
+        self.neighbors.retain(|_| *keep_iter.next().unwrap());
+        self.mpm.retain(&keep);
+        self.sph_current = false;
+    }
+
+    fn move_obstacles(&mut self, dt: f32) {
+        for obstacle in self.obstacles.iter_mut() {
+            obstacle.advance(dt);
+        }
+    }
+
+    // SPH does this in `handle_boundary`, the grid solvers after their step
+    fn collide_obstacles(&mut self) {
+        if self.obstacles.is_empty() {
+            return;
+        }
+        let obstacles = &self.obstacles;
+        let (lower, upper) = (Vec2::splat(KERNEL_RADIUS), Vec2::new(self.field.width, self.field.height) - KERNEL_RADIUS);
+        self.particles.par_iter_mut().for_each(|particle|{
+            for obstacle in obstacles {
+                obstacle.collide(&mut particle.position, &mut particle.velocity, PARTICLE_SIZE, RESTITUTION);
+            }
+            // An obstacle moving into a wall would push the particles out of the field
+            particle.position = particle.position.max(lower).min(upper);
+        });
+    }
+
+    // Grid solvers take the attractors and force fields as a velocity change, SPH applies them in `compute_force`
+    fn external_impulse(&mut self, dt: f32) {
+        if self.attractors.is_empty() && self.force_fields.is_empty() {
+            return;
+        }
+        let attractors = &self.attractors;
+        let force_fields = &self.force_fields;
+        let time = self.time;
+        self.particles.par_iter_mut().for_each(|particle|{
+            let acceleration = attractor_acceleration(attractors, particle.position)
+                + field_acceleration(force_fields, particle.position, particle.velocity, time);
+            particle.velocity += acceleration * dt;
+        });
+    }
+
+    // Returns the id of the field, which stays valid when other fields are removed
+    pub fn add_force_field(&mut self, field: Box<dyn ForceField>) -> u32 {
+        self.force_fields.push(field)
+    }
+
+    pub fn remove_force_field(&mut self, id: u32) {
+        self.force_fields.remove(id);
+    }
+
+    pub fn clear_force_fields(&mut self) {
+        self.force_fields.clear();
     }
 
-    fn get_mouse_radius(&self) -> f32 {
-        // 0.08 + (self.particles.len() as f32 - 3000.0) / 200000.0
-        self.field.height / 5.0
+    pub fn set_flip_ratio(&mut self, flip_ratio: f32) {
+        self.flip.flip_ratio = flip_ratio.clamp(0.0, 1.0);
     }
 
-    fn mouse_force(&mut self, mouse_vec: Vec2) {
-        let mouse_radius = self.get_mouse_radius();
+    // Grid solvers don't keep particles apart by themselves, so overlapping pairs are pushed apart.
+    // The pushes are collected first, so that every pair sees the positions before the pass.
+    fn separate_particles(&mut self) {
+        self.cells.register_cells(&self.particles);
+        // The positions alone are much more compact than the particles
+        self.positions.clear();
+        self.positions.extend(self.particles.iter().map(|particle| particle.position));
+        let positions = &self.positions;
+        let cells = &self.cells;
+        let (lower, upper) = (Vec2::splat(KERNEL_RADIUS), Vec2::new(self.field.width, self.field.height) - KERNEL_RADIUS);
+
+        self.separation.resize(positions.len(), Vec2::ZERO);
+        self.separation.par_iter_mut().enumerate().for_each(|(i, push)|{
+            let pi = positions[i];
+            *push = Vec2::ZERO;
+            cells.for_each_neighbor(pi, KERNEL_RADIUS, |j|{
+                let rij = pi - positions[j as usize];
+                let r2 = rij.length_squared();
+                if j as usize != i && EPS < r2 && r2 < PARTICLE_SIZE * PARTICLE_SIZE {
+                    let r = r2.sqrt();
+                    *push += 0.5 * (PARTICLE_SIZE - r) * rij / r;
+                }
+            });
+        });
+        // Same bounds as handle_boundary, the push must not move particles out of the field
+        self.particles.par_iter_mut().zip_eq(self.separation.par_iter()).for_each(|(particle, push)|{
+            particle.position = (particle.position + *push).max(lower).min(upper);
+        });
+    }
+
+    // Blends the dye of particles within `radius` of `center` toward `value` (1.0 paints, 0.0 erases).
+    pub fn paint_dye(&mut self, center: Vec2, radius: f32, value: f32) {
         self.particles.par_iter_mut().for_each(|particle|{
-            let dx = mouse_vec - particle.position;
-            if dx.length() < mouse_radius {
-                let dir = dx.normalize_or_zero();
-                particle.force += MOUSE_FORCE_STRENGTH * dir;
+            let dist = (particle.position - center).length();
+            if dist < radius {
+                let weight = 1.0 - dist / radius;
+                particle.dye += (value - particle.dye) * weight;
             }
         });
     }
@@ -140,13 +767,26 @@ impl State {
     fn handle_boundary(&mut self) {
         let field_height = self.field.height;
         let field_width = self.field.width;
+        let obstacles = &self.obstacles;
 
         self.particles.par_iter_mut().for_each(|particle|{
             particle.velocity += (particle.force / particle.density) * DT;
             particle.position += particle.velocity * DT;
+            particle.dye = (particle.dye + particle.dye_rate * DT).clamp(0.0, 1.0);
+            // Moving obstacles hand their velocity over to the particles they hit
+            for obstacle in obstacles {
+                obstacle.collide(&mut particle.position, &mut particle.velocity, PARTICLE_SIZE, RESTITUTION);
+            }
 
+            // Grains come to rest at the walls, bouncing would keep the grains next to them moving
+            let sand = particle.material == Material::Sand;
             if particle.position.y - KERNEL_RADIUS < 0.0 {
-                particle.velocity.y = (KERNEL_RADIUS - particle.position.y) / DT;
+                if sand {
+                    particle.position.y = KERNEL_RADIUS;
+                    sand_wall_contact(&mut particle.velocity.y, &mut particle.velocity.x, 1.0);
+                } else {
+                    particle.velocity.y = (KERNEL_RADIUS - particle.position.y) / DT;
+                }
             }
             if particle.position.y + KERNEL_RADIUS > field_height { 
                 particle.position.y = field_height - KERNEL_RADIUS;
@@ -154,11 +794,19 @@ impl State {
             }
             if particle.position.x - KERNEL_RADIUS < 0.0 {
                 particle.position.x = KERNEL_RADIUS;
-                particle.velocity.x *= -0.3;
+                if sand {
+                    sand_wall_contact(&mut particle.velocity.x, &mut particle.velocity.y, 1.0);
+                } else {
+                    particle.velocity.x *= -RESTITUTION;
+                }
             }
             if particle.position.x + KERNEL_RADIUS > field_width {
                 particle.position.x = field_width - KERNEL_RADIUS;
-                particle.velocity.x *= -0.3;
+                if sand {
+                    sand_wall_contact(&mut particle.velocity.x, &mut particle.velocity.y, -1.0);
+                } else {
+                    particle.velocity.x *= -RESTITUTION;
+                }
             }
         });
     }
@@ -175,6 +823,7 @@ impl State {
                 neighbors.clear();
                 let pi = &particles_copy[i];
                 particle.density = 0.0;
+                particle.near_density = 0.0;
 
                 let grid_x = (pi.position.x / KERNEL_RADIUS) as i32;
                 let grid_y = (pi.position.y / KERNEL_RADIUS) as i32;
@@ -201,12 +850,127 @@ impl State {
                     }
                 }
                 particle.pressure = STIFFNESS * (particle.density - TARGET_DENSITY);
+                if particle.material == Material::Sand {
+                    // Grains only push each other apart
+                    particle.pressure = particle.pressure.max(0.0);
+                }
                 particle.near_pressure = NEAR_STIFFNESS * particle.near_density;
             });
     }
 
+    // Elasto-plastic update of the shear stress of sand grains. 
+    // The stress grows with the shear rate and is capped by the Drucker-Prager yield 
+    // condition |s| <= SAND_FRICTION * p, so grains resist sliding in proportion to the 
+    // confining pressure and have no cohesion. This is what lets sand pile up with an angle of repose.
+    // The pressure is that of the contacts with the neighbours: in a resting pile the density barely 
+    // exceeds TARGET_DENSITY, and the grains are held apart by the near pressure.
+    fn update_stress(&mut self) {
+        if self.particles.iter().all(|particle| particle.material != Material::Sand) {
+            return;
+        }
+        let particles_copy = self.particles.clone();
+
+        self.particles
+            .par_iter_mut()
+            .zip_eq(self.neighbors.par_iter())
+            .enumerate()
+            .for_each(|(i, (particle, neighbors))|{
+                let pi = &particles_copy[i];
+                if pi.material != Material::Sand {
+                    return;
+                }
+
+                // Velocity gradient by least squares over the sand neighbors
+                let mut dv_dx = Mat2::ZERO;
+                let mut dx_dx = Mat2::ZERO;
+                let mut confining_pressure = 0.0;
+                let mut total_weight = 0.0;
+                for Neighbor{ r, j } in neighbors {
+                    let pj = &particles_copy[*j as usize];
+                    confining_pressure += (KERNEL_RADIUS - *r) * contact_pressure(pi, pj, *r);
+                    total_weight += KERNEL_RADIUS - *r;
+                    if pj.material != Material::Sand {
+                        continue;
+                    }
+                    let q = 1.0 - *r * *r / KERNEL_RADIUS_SQ;
+                    let w = q * q * q;
+                    let dx = pj.position - pi.position;
+                    let dv = pj.velocity - pi.velocity;
+                    dv_dx += w * Mat2::from_cols(dv * dx.x, dv * dx.y);
+                    dx_dx += w * Mat2::from_cols(dx * dx.x, dx * dx.y);
+                }
+                if dx_dx.determinant().abs() < KERNEL_RADIUS_POW8 {
+                    particle.stress = Mat2::ZERO;
+                    return;
+                }
+                let grad_v = dv_dx * dx_dx.inverse();
+
+                // Jaumann rate of the deviatoric stress (Hooke's law in shear)
+                let strain_rate = (grad_v + grad_v.transpose()) * 0.5;
+                let spin = (grad_v - grad_v.transpose()) * 0.5;
+                let trace = strain_rate.x_axis.x + strain_rate.y_axis.y;
+                let deviatoric_rate = strain_rate - Mat2::IDENTITY * (trace * 0.5);
+                let s = pi.stress;
+                let stress_rate = 2.0 * SAND_SHEAR_MODULUS * deviatoric_rate + spin * s - s * spin;
+                let mut stress = s + stress_rate * DT;
+
+                // Return mapping onto the yield surface
+                let norm = (0.5 * (stress.x_axis.length_squared() + stress.y_axis.length_squared())).sqrt();
+                let max_norm = SAND_FRICTION * confining_pressure / total_weight;
+                if norm > max_norm {
+                    stress *= if norm > EPS { max_norm / norm } else { 0.0 };
+                }
+                particle.stress = stress;
+            });
+    }
+
+    // Coulomb friction between sand grains, at most SAND_FRICTION times the force pressing them together.
+    // It acts against the relative velocity that the other forces would give the pair in this substep, 
+    // so that static friction can hold a pile at rest. Runs after `compute_force`.
+    fn sand_friction(&mut self) {
+        if self.particles.iter().all(|particle| particle.material != Material::Sand) {
+            return;
+        }
+        let particles_copy = self.particles.clone();
+
+        self.particles
+            .par_iter_mut()
+            .zip_eq(self.neighbors.par_iter())
+            .enumerate()
+            .for_each(|(i, (particle, neighbors))|{
+                let pi = &particles_copy[i];
+                if pi.material != Material::Sand {
+                    return;
+                }
+                let vi = pi.velocity + pi.force / pi.density * DT;
+                let mut ffriction = Vec2::new(0.0, 0.0);
+                for Neighbor{ r, j } in neighbors {
+                    let pj = &particles_copy[*j as usize];
+                    if pj.material != Material::Sand {
+                        continue;
+                    }
+                    let dir = (pj.position - pi.position) / *r;
+                    let normal_force = MASS * SPIKY_POW2_GRAD * (KERNEL_RADIUS - *r) / pj.density * contact_pressure(pi, pj, *r);
+                    let relative_velocity = pj.velocity + pj.force / pj.density * DT - vi;
+                    let tangential = relative_velocity - relative_velocity.dot(dir) * dir;
+                    let speed = tangential.length();
+                    if speed > EPS {
+                        // Static friction only takes out the sliding, shared with the other neighbours and the partner
+                        let stop_force = pi.density * speed / (2.0 * DT * neighbors.len() as f32);
+                        ffriction += (SAND_FRICTION * normal_force / SAND_DENSITY_RATIO).min(stop_force) * tangential / speed;
+                    }
+                }
+                particle.force += ffriction;
+            });
+    }
+
     fn compute_force(&mut self) {
         let particles_copy = self.particles.clone();
+        let dye_diffusion = self.dye_diffusion;
+        let gravity = self.gravity;
+        let attractors = &self.attractors;
+        let force_fields = &self.force_fields;
+        let time = self.time;
 
         self.particles
             .par_iter_mut()
@@ -215,6 +979,8 @@ impl State {
             .for_each(|(i, (particle, neighbors))|{
                 let mut fpress = Vec2::new(0.0, 0.0);
                 let mut fvisc = Vec2::new(0.0, 0.0);
+                let mut dye_rate = 0.0;
+                let mut fstress = Vec2::new(0.0, 0.0);
                 let pi = &particles_copy[i];
 
                 for Neighbor{ r, j } in neighbors {
@@ -227,26 +993,50 @@ impl State {
                     let press_coeff = -MASS * shared_pressure * SPIKY_POW2_GRAD * a / pj.density;
                     let near_shared_pressure = (pi.near_pressure + pj.near_pressure) * 0.5;
                     let near_press_coeff = -MASS * near_shared_pressure * SPIKY_POW3_GRAD * a * a / pj.near_density; 
-                    fpress += (press_coeff + near_press_coeff) * rij.normalize();
+                    let dir = rij.normalize();
+                    fpress += (press_coeff + near_press_coeff) * dir;
 
                     // Viscosity
                     let aa = KERNEL_RADIUS_SQ - *r * *r;
                     let visc_coeff = VISCOSITY * MASS * VISC_LAP * aa * aa * aa / pj.density;
                     let relative_speed = pj.velocity - pi.velocity;
                     fvisc += visc_coeff * relative_speed;
+
+                    if pi.material == Material::Sand && pj.material == Material::Sand {
+                        // Shear stress carried by the grain skeleton
+                        let shared_stress = (pi.stress + pj.stress) * 0.5;
+                        fstress += MASS * SPIKY_POW2_GRAD * a / pj.density * (shared_stress * dir);
+                    }
+
+                    // Dye diffusion (same kernel as viscosity)
+                    if dye_diffusion > 0.0 {
+                        let diff_coeff = dye_diffusion * MASS * VISC_LAP * aa * aa * aa / pj.density;
+                        dye_rate += diff_coeff * (pj.dye - pi.dye);
+                    }
                 }
 
-                let fgrv = pi.density * GRV;
-                particle.force = fpress + fvisc + fgrv;
+                let acceleration = gravity
+                    + attractor_acceleration(attractors, pi.position)
+                    + field_acceleration(force_fields, pi.position, pi.velocity, time);
+                // External accelerations are the same for every material, the heavier sand only
+                // responds less to the pressure of the water around it and sinks
+                let finternal = match pi.material {
+                    Material::Sand => (fpress + fvisc + fstress) / SAND_DENSITY_RATIO, 
+                    _ => fpress + fvisc + fstress, 
+                };
+                particle.force = finternal + pi.density * acceleration;
+                particle.dye_rate = dye_rate / pi.density;
             });
     }
 
     fn clear(&mut self) {
         self.particles.clear();
         self.neighbors.clear();
+        self.mpm.clear();
+        self.sph_current = false;
     }
 
-    fn add_particle(&mut self, position: Vec2) {
+    fn add_particle(&mut self, position: Vec2, dye: f32, material: Material) {
         let velocity = Vec2::new(0.0, 0.0);
         let force = Vec2::new(0.0, 0.0);
         let pressure = 0.0;
@@ -254,37 +1044,75 @@ impl State {
         let density = 0.0;
         let near_density = 0.0;
         let size = PARTICLE_SIZE;
+        let dye_rate = 0.0;
 
-        self.particles.push(Particle{position, velocity, force, pressure, near_pressure, density, near_density, size});
+        self.particles.push(Particle{position, velocity, force, pressure, near_pressure, density, near_density, size, dye, dye_rate, material, stress: Mat2::ZERO});
         self.neighbors.push(Vec::new());
+        self.mpm.push_particle();
+        self.sph_current = false;
     }
 
-    pub fn height_from_num_particles(num_particles: u32) -> f32 { 
-        // Determined experimentally
-        0.4 + (num_particles as f32 - 3000.0) / 30000.0
-        // 0.7
+    // Changes the domain without a reset. Particles outside the new domain are moved back in.
+    // Sizes below MIN_FIELD_SIZE (a hidden canvas) are ignored, sizes above MAX_FIELD_SIZE are clamped.
+    // When the field shrinks below the particles, the occupied part is scaled down to fit. That compresses
+    // the fluid, which splashes as the pressure pushes it apart again, but less than when every particle
+    // outside were moved onto the wall.
+    pub fn resize_field(&mut self, width: f32, height: f32) {
+        if !(width >= MIN_FIELD_SIZE && height >= MIN_FIELD_SIZE && width.is_finite() && height.is_finite()) {
+            return;
+        }
+        let (width, height) = (width.min(MAX_FIELD_SIZE), height.min(MAX_FIELD_SIZE));
+        self.field = Field { height, width };
+        self.cells = Cells::new(height, width, KERNEL_RADIUS);
+        self.mpm.resize(&self.field);
+        self.flip.resize(&self.field);
+
+        let min = Vec2::splat(KERNEL_RADIUS);
+        let max = Vec2::new(width, height) - KERNEL_RADIUS;
+        let extent = self.particles.iter().fold(min, |extent, particle| extent.max(particle.position));
+        // Per axis, 1 where the particles still fit
+        let scale = ((max - min) / (extent - min).max(Vec2::splat(EPS))).min(Vec2::ONE);
+        self.particles.par_iter_mut().for_each(|particle|{
+            particle.position = (min + (particle.position - min) * scale).max(min).min(max);
+        });
+        self.sph_current = false;
     }
 
-    pub fn init_particles(&mut self, num_particles: u32, aspect_ratio: f32) {
+    // Fills a block standing on the floor at the left of the field. When the particles don't fit
+    // at the requested fill level the block gets taller, and when the field is full the rest are dropped.
+    pub fn init_particles(&mut self, scene: &Scene) {
+        let num_particles = scene.num_particles.min(MAX_PARTICLES);
         self.clear();
         self.particles.reserve(num_particles as usize);
-        let height = Self::height_from_num_particles(num_particles);
-        let width = height * aspect_ratio;
-        log(&height.to_string());
-        log(&width.to_string());
-        self.field = Field { height, width };
+        self.field = scene.field();
+        let (height, width) = (self.field.height, self.field.width);
         self.cells = Cells::new(height, width, KERNEL_RADIUS);
+        self.mpm = Mpm::new(&self.field, KERNEL_RADIUS);
+        self.flip = Flip::new(&self.field, KERNEL_RADIUS);
+        // Timings of another particle count would blur the statistics
+        self.profiler.clear();
+        if num_particles == 0 {
+            return;
+        }
+
+        let num_liquid = ((1.0 - scene.top_fraction.clamp(0.0, 1.0)) * num_particles as f32) as usize;
+        let fill_height = scene.fill_level.clamp(0.01, 1.0) * height;
+        let left = width * 0.1;
+        let block_width = (num_particles as f32 * PARTICLE_SIZE * PARTICLE_SIZE / fill_height).max(PARTICLE_SIZE).min(width * 0.8);
 
         let seed = 12345; 
         let mut rng = StdRng::seed_from_u64(seed);
 
-        let mut y = 20.0 * KERNEL_RADIUS;
-        loop {
-            let mut x = self.field.width * 0.1;
+        let mut y = KERNEL_RADIUS;
+        while y < height - KERNEL_RADIUS {
+            let mut x = left;
             loop {
-                self.add_particle(Vec2::new(x, y));
+                // Left half starts dyed so that mixing is visible from the first frame
+                let dye = if x < left + block_width * 0.5 { 1.0 } else { 0.0 };
+                let material = if self.particles.len() < num_liquid { Material::Liquid } else { scene.top_material };
+                self.add_particle(Vec2::new(x, y), dye, material);
                 x += PARTICLE_SIZE + 0.0001 * rng.gen::<f32>();
-                if x > self.field.width * 0.9 {
+                if x > left + block_width {
                     break;
                 }
                 if self.particles.len() == num_particles as usize {
@@ -299,6 +1127,118 @@ impl State {
     }
 }
 
+fn tool_acceleration(tool: Tool, radius: f32, pointer: Pointer, particle: &Particle) -> Vec2 {
+    // A zero radius reaches no particle, and would divide zero by zero for one right at the pointer
+    if radius <= 0.0 {
+        return Vec2::new(0.0, 0.0);
+    }
+    let d = pointer.position - particle.position;
+    let q2 = d.length_squared() / (radius * radius);
+    if q2 >= 1.0 {
+        return Vec2::new(0.0, 0.0);
+    }
+    // Smooth falloff, 1 at the pointer and 0 with zero slope at the edge
+    let weight = (1.0 - q2) * (1.0 - q2);
+    let dir = d.normalize_or_zero();
+    match tool.mode {
+        ToolMode::Attract => tool.strength * weight * dir, 
+        ToolMode::Repel => -tool.strength * weight * dir, 
+        ToolMode::Swirl => tool.strength * weight * dir.perp(), 
+        ToolMode::Drag => DRAG_RATE * tool.strength / DEFAULT_TOOL_STRENGTH * weight * (pointer.velocity - particle.velocity), 
+        ToolMode::Add | ToolMode::Delete => Vec2::new(0.0, 0.0), 
+    }
+}
+
+// Removes the velocity into a wall with inward normal `inward` (+1 or -1 along the axis of `normal_velocity`)
+// and slows the sliding along it by SAND_FRICTION times the removed velocity
+fn sand_wall_contact(normal_velocity: &mut f32, tangential_velocity: &mut f32, inward: f32) {
+    let impulse = (-inward * *normal_velocity).max(0.0);
+    *normal_velocity += inward * impulse;
+    *tangential_velocity = tangential_velocity.signum() * (tangential_velocity.abs() - SAND_FRICTION * impulse).max(0.0);
+}
+
+// Pressure holding two neighbours apart, in the units of `Particle::pressure`: the positive part of the 
+// pressure plus the near pressure, which `compute_force` applies with a steeper kernel
+fn contact_pressure(pi: &Particle, pj: &Particle, r: f32) -> f32 {
+    let shared_pressure = ((pi.pressure + pj.pressure) * 0.5).max(0.0);
+    let near_shared_pressure = (pi.near_pressure + pj.near_pressure) * 0.5;
+    let near_ratio = SPIKY_POW3_GRAD * (KERNEL_RADIUS - r) / SPIKY_POW2_GRAD * pj.density / pj.near_density;
+    shared_pressure + near_shared_pressure * near_ratio
+}
+
+fn attractor_acceleration(attractors: &[Attractor], position: Vec2) -> Vec2 {
+    attractors.iter().fold(Vec2::new(0.0, 0.0), |acc, attractor|{
+        let d = attractor.position - position;
+        let r2 = d.length_squared();
+        let radius2 = attractor.radius * attractor.radius;
+        // A point attractor without a core is singular at its centre
+        if radius2 <= 0.0 {
+            return acc;
+        }
+        acc + attractor.strength * radius2 / (r2 + radius2) * d.normalize_or_zero()
+    })
+}
+
+fn field_acceleration(force_fields: &[Box<dyn ForceField>], position: Vec2, velocity: Vec2, time: f32) -> Vec2 {
+    force_fields.iter().fold(Vec2::new(0.0, 0.0), |acc, field| acc + field.acceleration(position, velocity, time))
+}
+
+// SPH interpolation of the particle quantities with the kernel of the density computation
+pub struct FieldSampler<'a> {
+    particles: &'a [Particle], 
+    cells: Cells, 
+}
+
+// Interpolated values at one point. Velocity and pressure are zero where there is (almost) no fluid.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FieldSample {
+    pub density: f32, 
+    pub velocity: Vec2, 
+    pub pressure: f32, 
+}
+
+// Samples at the centres of a regular grid over the field, row-major with the rows from the bottom up
+#[derive(Clone, Debug, Default)]
+pub struct FieldGrid {
+    pub nx: usize, 
+    pub ny: usize, 
+    pub density: Vec<f32>, 
+    // x and y interleaved
+    pub velocity: Vec<f32>, 
+    pub pressure: Vec<f32>, 
+}
+
+impl FieldSampler<'_> {
+    // Density as in the SPH solver, velocity and pressure as kernel weighted averages of the neighbours.
+    // The pressure is that of the last SPH step, like ParticleQuantity::Pressure.
+    pub fn sample(&self, point: Vec2) -> FieldSample {
+        let mut density = 0.0;
+        let mut velocity = Vec2::ZERO;
+        let mut pressure = 0.0;
+        for j in self.cells.neighbors_of_point(point, KERNEL_RADIUS) {
+            let pj = &self.particles[j as usize];
+            let r2 = (pj.position - point).length_squared();
+            if r2 < KERNEL_RADIUS_SQ {
+                let a = KERNEL_RADIUS_SQ - r2;
+                let w = MASS * SPIKY_POW2 * a * a;
+                density += w;
+                velocity += w * pj.velocity;
+                pressure += w * pj.pressure;
+            }
+        }
+        if density < EMPTY_DENSITY {
+            return FieldSample { density, ..Default::default() };
+        }
+        FieldSample { density, velocity: velocity / density, pressure: pressure / density }
+    }
+
+    // None where there is (almost) no fluid
+    pub fn velocity(&self, point: Vec2) -> Option<Vec2> {
+        let sample = self.sample(point);
+        (sample.density >= EMPTY_DENSITY).then_some(sample.velocity)
+    }
+}
+
 impl Cells {
     pub fn new(height: f32, width: f32, radius: f32) -> Self {
         let ny = (height / radius).ceil() as usize;
@@ -311,32 +1251,330 @@ impl Cells {
         self.nx * iy + ix
     }
 
-    pub fn register_cells(&mut self, particles: &Vec<Particle>) {
+    pub fn register_cells(&mut self, particles: &[Particle]) {
         self.cells.iter_mut().for_each(|v| v.clear());
         particles.iter().enumerate().for_each(|(i, particle)|{
-            let ix = (particle.position.x / KERNEL_RADIUS) as usize;
-            let iy = (particle.position.y / KERNEL_RADIUS) as usize;
+            // Particles outside the field go to the nearest cell
+            let ix = ((particle.position.x / KERNEL_RADIUS) as usize).min(self.nx - 1);
+            let iy = ((particle.position.y / KERNEL_RADIUS) as usize).min(self.ny - 1);
             let cell_id = self.cell_position_to_id(ix, iy);
             self.cells[cell_id].push(i as u32);
         });
     }
 
     pub fn neighbors(&self, particle: &Particle, radius: f32) -> Vec<u32> {
-        let ix = (particle.position.x / radius) as i32;
-        let iy = (particle.position.y / radius) as i32;
+        self.neighbors_of_point(particle.position, radius)
+    }
+
+    pub fn neighbors_of_point(&self, position: Vec2, radius: f32) -> Vec<u32> {
+        let mut v = Vec::new();
+        self.for_each_cell(position, radius, |cell| v.extend_from_slice(cell));
+        v
+    }
+
+    // Same particles as `neighbors_of_point`, without collecting them
+    pub fn for_each_neighbor<F>(&self, position: Vec2, radius: f32, mut f: F)
+    where F: FnMut(u32)
+    {
+        self.for_each_cell(position, radius, |cell| cell.iter().for_each(|j| f(*j)));
+    }
+
+    fn for_each_cell<F>(&self, position: Vec2, radius: f32, mut f: F)
+    where F: FnMut(&[u32])
+    {
+        let ix = (position.x / radius) as i32;
+        let iy = (position.y / radius) as i32;
         let dx_ = [-1, 0, 1];
         let dy_ = [-1, 0, 1];
 
-        let mut v = Vec::new();
         for dx in dx_ {
             for dy in dy_ {
                 let jx = ix + dx;
                 let jy  = iy + dy;
                 if 0 <= jx && jx < self.nx as i32 && 0 <= jy && jy < self.ny as i32 {
-                    v.extend_from_slice(&self.cells[self.cell_position_to_id(jx as usize, jy as usize)]);
+                    f(&self.cells[self.cell_position_to_id(jx as usize, jy as usize)]);
                 }
             }
         }
-        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::obstacle::{Keyframe, Shape};
+
+    fn scene(num_particles: u32) -> Scene {
+        Scene { num_particles, field_width: 0.6, field_height: 0.4, ..Default::default() }
+    }
+
+    fn inside(state: &State) -> bool {
+        let max = Vec2::new(state.field.width, state.field.height);
+        state.particles.iter().all(|particle| particle.position.cmpge(Vec2::ZERO).all() && particle.position.cmple(max).all())
+    }
+
+    // Height and the width holding 98% of the particles
+    fn pile_size(state: &State) -> (f32, f32) {
+        let mut xs: Vec<f32> = state.particles.iter().map(|particle| particle.position.x).collect();
+        xs.sort_by(f32::total_cmp);
+        let height = state.particles.iter().map(|particle| particle.position.y).fold(0.0, f32::max);
+        (height, xs[xs.len() * 99 / 100] - xs[xs.len() / 100])
+    }
+
+    #[test]
+    fn sand_piles_up() {
+        let run = |material| {
+            let mut state = State::new(&Scene { top_material: material, top_fraction: 1.0, ..scene(800) });
+            for _ in 0..300 {
+                state.update(&[]);
+            }
+            state
+        };
+        let water = run(Material::Liquid);
+        let sand = run(Material::Sand);
+        let (water_height, water_width) = pile_size(&water);
+        let (sand_height, sand_width) = pile_size(&sand);
+        // Water spreads over the floor, sand keeps a pile of at least three times the height
+        assert!(sand_height > 3.0 * water_height, "{} {}", sand_height, water_height);
+        assert!(sand_width < 0.6 * water_width, "{} {}", sand_width, water_width);
+        // and comes to rest
+        let kinetic_energy = sand.diagnostics().kinetic_energy / sand.particles.len() as f32;
+        assert!(kinetic_energy < 1e-6, "{}", kinetic_energy);
+    }
+
+    #[test]
+    fn zero_radius_tool() {
+        let mut state = State::new(&scene(200));
+        state.tool.radius = 0.0;
+        state.attractors.push(Attractor { position: state.particles[0].position, strength: 1.0, radius: 0.0 });
+        let pointer = Pointer { position: state.particles[0].position, velocity: Vec2::ZERO };
+        for solver_kind in [SolverKind::Sph, SolverKind::Mpm, SolverKind::Flip] {
+            state.solver_kind = solver_kind;
+            state.update(&[pointer]);
+            assert!(state.particles.iter().all(|particle| particle.position.is_finite() && particle.velocity.is_finite()), "{:?}", solver_kind);
+        }
+    }
+
+    #[test]
+    fn obstacle_pushes_into_wall() {
+        for solver_kind in [SolverKind::Sph, SolverKind::Mpm, SolverKind::Flip] {
+            let mut state = State::new(&scene(1000));
+            state.solver_kind = solver_kind;
+            // Sweeps up through the block and out through the ceiling
+            let keyframes = vec![
+                Keyframe { time: 0.0, position: Vec2::new(0.1, 0.02), angle: 0.0 },
+                Keyframe { time: 0.2, position: Vec2::new(0.1, 0.5), angle: 0.0 },
+            ];
+            let obstacle = Obstacle::new(Shape::Box { half_extents: Vec2::new(0.1, 0.02) }, Vec2::ZERO, 0.0);
+            state.obstacles.push(obstacle.with_keyframes(keyframes));
+            for _ in 0..15 {
+                state.update(&[]);
+                assert!(inside(&state), "{:?}", solver_kind);
+            }
+        }
+    }
+
+    #[test]
+    fn sampler_interpolates_the_neighbors() {
+        let mut state = State::new(&scene(400));
+        for particle in &mut state.particles {
+            particle.velocity = Vec2::new(0.5, -0.25);
+            particle.pressure = 2.0;
+        }
+        let sampler = state.sampler();
+        let point = state.particles[200].position + Vec2::splat(0.3 * KERNEL_RADIUS);
+        let sample = sampler.sample(point);
+        // Density is the kernel sum over all particles, a constant field is reproduced exactly
+        let density: f32 = state.particles.iter().map(|particle|{
+            let a = (KERNEL_RADIUS_SQ - (particle.position - point).length_squared()).max(0.0);
+            MASS * SPIKY_POW2 * a * a
+        }).sum();
+        assert!((sample.density - density).abs() < 1e-3 * density, "{} {}", sample.density, density);
+        assert!(sample.density >= EMPTY_DENSITY);
+        assert!((sample.velocity - Vec2::new(0.5, -0.25)).length() < 1e-5, "{}", sample.velocity);
+        assert!((sample.pressure - 2.0).abs() < 1e-5, "{}", sample.pressure);
+        assert_eq!(sampler.velocity(point), Some(sample.velocity));
+    }
+
+    #[test]
+    fn sampler_is_empty_away_from_fluid() {
+        let mut state = State::new(&scene(400));
+        state.particles.iter_mut().for_each(|particle| particle.velocity = Vec2::ONE);
+        let sampler = state.sampler();
+        let point = Vec2::new(state.field.width, state.field.height) - KERNEL_RADIUS;
+        assert_eq!(sampler.sample(point), FieldSample::default());
+        assert_eq!(sampler.velocity(point), None);
+        // Outside the field as well
+        assert_eq!(sampler.sample(Vec2::new(-1.0, 2.0)), FieldSample::default());
+    }
+
+    #[test]
+    fn sample_grid_is_row_major_from_the_bottom() {
+        let mut state = State::new(&scene(400));
+        for (i, particle) in state.particles.iter_mut().enumerate() {
+            particle.velocity = Vec2::new(i as f32, 1.0);
+        }
+        let (nx, ny) = (12, 5);
+        let grid = state.sample_grid(nx, ny);
+        assert_eq!((grid.nx, grid.ny), (nx, ny));
+        assert_eq!((grid.density.len(), grid.velocity.len(), grid.pressure.len()), (nx * ny, 2 * nx * ny, nx * ny));
+        let cell = Vec2::new(state.field.width / nx as f32, state.field.height / ny as f32);
+        for (i, j) in [(0, 0), (3, 0), (1, 2), (nx - 1, ny - 1)] {
+            let k = j * nx + i;
+            let sample = state.sample_point((Vec2::new(i as f32, j as f32) + 0.5) * cell);
+            assert_eq!(grid.density[k], sample.density);
+            assert_eq!(Vec2::new(grid.velocity[2 * k], grid.velocity[2 * k + 1]), sample.velocity);
+            assert_eq!(grid.pressure[k], sample.pressure);
+        }
+        // The block sits at the bottom, in the second column
+        assert!(grid.density[1] > EMPTY_DENSITY);
+        assert_eq!(grid.density[nx * ny - 1], 0.0);
+    }
+
+    #[test]
+    fn diagnostics_totals() {
+        let mut state = State::new(&scene(300));
+        let velocity = Vec2::new(0.3, -0.4);
+        state.particles.iter_mut().for_each(|particle| particle.velocity = velocity);
+        let n = state.particles.len() as f32;
+        let height: f32 = state.particles.iter().map(|particle| particle.position.y).sum();
+
+        let diagnostics = state.diagnostics();
+        assert_eq!(diagnostics.particle_count, 300);
+        assert_eq!(diagnostics.time, 0.0);
+        assert!((diagnostics.kinetic_energy - 0.5 * MASS * n * 0.25).abs() < 1e-3, "{}", diagnostics.kinetic_energy);
+        assert!((diagnostics.momentum - MASS * n * velocity).length() < 1e-3, "{}", diagnostics.momentum);
+        assert!((diagnostics.potential_energy + MASS * state.gravity.y * height).abs() < 1e-3, "{}", diagnostics.potential_energy);
+        assert!((diagnostics.max_speed - 0.5).abs() < 1e-6);
+        assert!(diagnostics.min_neighbors >= 1 && diagnostics.min_neighbors <= diagnostics.max_neighbors);
+        assert!(diagnostics.mean_neighbors >= diagnostics.min_neighbors as f32 && diagnostics.mean_neighbors <= diagnostics.max_neighbors as f32);
+        assert!(diagnostics.mean_density_error >= 0.0 && diagnostics.mean_density_error <= diagnostics.max_density_error);
+    }
+
+    #[test]
+    fn diagnostics_of_no_particles() {
+        let state = State::new(&scene(0));
+        let diagnostics = state.diagnostics();
+        assert_eq!(diagnostics.particle_count, 0);
+        assert_eq!((diagnostics.min_neighbors, diagnostics.max_neighbors), (0, 0));
+        assert_eq!(diagnostics.mean_neighbors, 0.0);
+        assert_eq!(diagnostics.kinetic_energy, 0.0);
+    }
+
+    #[test]
+    fn diagnostics_after_a_step() {
+        // With the neighbours of the last SPH substep instead of a pass of their own
+        let mut state = State::new(&scene(300));
+        state.update(&[]);
+        let diagnostics = state.diagnostics();
+        assert!((diagnostics.time - SOLVER_STEPS as f32 * DT).abs() < 1e-6, "{}", diagnostics.time);
+        assert!(diagnostics.min_neighbors >= 1);
+        assert!(diagnostics.kinetic_energy > 0.0 && diagnostics.max_speed > 0.0);
+    }
+
+    // Substeps run so far, from the simulated time
+    fn substeps_run(state: &State) -> u32 {
+        (state.time / DT).round() as u32
+    }
+
+    #[test]
+    fn substeps_follow_the_time_scale() {
+        let mut state = State::new(&scene(50));
+        state.update(&[]);
+        assert_eq!(substeps_run(&state), SOLVER_STEPS);
+
+        let mut state = State::new(&scene(50));
+        state.set_time_scale(MAX_TIME_SCALE);
+        state.update(&[]);
+        assert_eq!(substeps_run(&state), 4 * SOLVER_STEPS);
+
+        // Slow motion carries the fractions over, 10 substeps in 32 frames
+        let mut state = State::new(&scene(50));
+        state.set_time_scale(MIN_TIME_SCALE);
+        let mut counts = Vec::new();
+        for _ in 0..32 {
+            state.update(&[]);
+            counts.push(substeps_run(&state));
+        }
+        assert_eq!(&counts[..4], &[0, 0, 0, 1]);
+        assert_eq!(counts[31], SOLVER_STEPS);
+    }
+
+    #[test]
+    fn single_step_runs_at_least_one_substep() {
+        // A whole frame at normal speed
+        let mut state = State::new(&scene(50));
+        state.single_step(&[]);
+        assert_eq!(substeps_run(&state), SOLVER_STEPS);
+
+        // A single substep in slow motion, where the frame would have none
+        let mut state = State::new(&scene(50));
+        state.set_time_scale(MIN_TIME_SCALE);
+        state.single_step(&[]);
+        assert_eq!(substeps_run(&state), 1);
+        // and the following frames aren't delayed by it
+        for _ in 0..3 {
+            state.update(&[]);
+        }
+        assert_eq!(substeps_run(&state), 1);
+        state.update(&[]);
+        assert_eq!(substeps_run(&state), 2);
+        // Only the next frame is forced
+        state.single_step(&[]);
+        state.update(&[]);
+        assert_eq!(substeps_run(&state), 3);
+    }
+
+    #[test]
+    fn unbounded_scene_is_clamped() {
+        let state = State::new(&Scene { field_width: f32::INFINITY, ..scene(100) });
+        assert_eq!(state.field.width, MAX_FIELD_SIZE);
+        assert_eq!(state.particles.len(), 100);
+        let state = State::new(&Scene { field_width: f32::NAN, field_height: f32::INFINITY, ..scene(100) });
+        assert!(state.field.width.is_finite() && state.field.height == MAX_FIELD_SIZE);
+        // As many as fit into the field, without reserving for all of them
+        let state = State::new(&scene(u32::MAX));
+        assert!(state.particles.len() < MAX_PARTICLES as usize && inside(&state));
+
+        let mut state = State::new(&scene(100));
+        state.resize_field(f32::MAX, 0.5);
+        assert_eq!((state.field.width, state.field.height), (MAX_FIELD_SIZE, 0.5));
+    }
+
+    #[test]
+    fn near_pressure_of_a_settled_scene_is_steady() {
+        let mut state = State::new(&scene(400));
+        let mean_near_pressure = |state: &State| {
+            let values = state.particle_quantity(ParticleQuantity::NearPressure);
+            values.iter().sum::<f32>() / values.len() as f32
+        };
+        for _ in 0..60 {
+            state.update(&[]);
+        }
+        let early = mean_near_pressure(&state);
+        for _ in 0..60 {
+            state.update(&[]);
+        }
+        let late = mean_near_pressure(&state);
+        // Computed from the neighbours of the step, not summed up over the steps
+        assert!(early > 0.0 && (late / early - 1.0).abs() < 0.2, "{} {}", early, late);
+    }
+
+    #[test]
+    fn resize_compresses_a_settled_fluid() {
+        let mut state = State::new(&scene(1000));
+        for _ in 0..100 {
+            state.update(&[]);
+        }
+        state.resize_field(0.5 * state.field.width, state.field.height);
+        assert!(inside(&state));
+        for _ in 0..30 {
+            state.update(&[]);
+            assert!(inside(&state));
+            assert!(state.particles.iter().all(|particle| particle.velocity.is_finite()));
+        }
+        // Hidden canvas
+        state.resize_field(0.0, 0.0);
+        assert!(state.field.width > 0.0 && inside(&state));
     }
 }
@@ -0,0 +1,187 @@
+use glam::Vec2;
+
+// Smooth fluid surface: particles are splatted into a scalar field on a regular grid
+// and the isocontour is extracted with marching squares.
+// Independent of WebGL, the output is plain geometry in field coordinates.
+
+// Splatted density on the grid nodes, about 1.0 inside a fluid at rest and 0.0 outside
+pub struct DensityGrid {
+    pub nx: usize, 
+    pub ny: usize, 
+    pub spacing: f32, 
+    pub values: Vec<f32>, 
+}
+
+// Filled body as a triangle list and the outline as a line list (pairs of points)
+#[derive(Default)]
+pub struct Contour {
+    pub triangles: Vec<Vec2>, 
+    pub segments: Vec<Vec2>, 
+}
+
+impl DensityGrid {
+    // `particle_spacing` is the distance between neighbouring particles at rest, used for the normalization.
+    // `radius` is the splat radius, larger radii give a smoother and rounder surface.
+    pub fn splat(positions: &[Vec2], width: f32, height: f32, spacing: f32, radius: f32, particle_spacing: f32) -> Self {
+        let nx = (width / spacing).ceil() as usize + 1;
+        let ny = (height / spacing).ceil() as usize + 1;
+        let mut values = vec![0.0; nx * ny];
+
+        // Integral of the kernel over the disc is π r² / 4, divided by the area per particle
+        let normalization = 4.0 * particle_spacing * particle_spacing / (std::f32::consts::PI * radius * radius);
+        let reach = (radius / spacing).ceil() as i32;
+        for position in positions {
+            let ci = (position.x / spacing).round() as i32;
+            let cj = (position.y / spacing).round() as i32;
+            for j in (cj - reach).max(0)..=(cj + reach).min(ny as i32 - 1) {
+                for i in (ci - reach).max(0)..=(ci + reach).min(nx as i32 - 1) {
+                    let node = Vec2::new(i as f32, j as f32) * spacing;
+                    let q2 = (node - *position).length_squared() / (radius * radius);
+                    if q2 < 1.0 {
+                        let w = 1.0 - q2;
+                        values[j as usize * nx + i as usize] += normalization * w * w * w;
+                    }
+                }
+            }
+        }
+        DensityGrid { nx, ny, spacing, values }
+    }
+
+    pub fn value(&self, i: usize, j: usize) -> f32 {
+        self.values[j * self.nx + i]
+    }
+
+    fn node(&self, i: usize, j: usize) -> Vec2 {
+        Vec2::new(i as f32, j as f32) * self.spacing
+    }
+}
+
+// Each cell contributes the part of it above `iso` as a polygon, found by walking the corners
+// counterclockwise and inserting the edge crossings. Saddle cells come out joined.
+// The outline consists of the polygon edges that run from crossing to crossing.
+pub fn marching_squares(grid: &DensityGrid, iso: f32) -> Contour {
+    let mut contour = Contour::default();
+    if grid.nx < 2 || grid.ny < 2 {
+        return contour;
+    }
+    for j in 0..grid.ny - 1 {
+        for i in 0..grid.nx - 1 {
+            let corners = [(i, j), (i + 1, j), (i + 1, j + 1), (i, j + 1)];
+            let values = corners.map(|(ci, cj)| grid.value(ci, cj));
+            let inside = values.map(|v| v > iso);
+            if inside.iter().all(|x| !*x) {
+                continue;
+            }
+            let points = corners.map(|(ci, cj)| grid.node(ci, cj));
+            if inside.iter().all(|x| *x) {
+                contour.triangles.extend_from_slice(&[points[0], points[1], points[2], points[0], points[2], points[3]]);
+                continue;
+            }
+
+            // (point, is an edge crossing)
+            let mut polygon: Vec<(Vec2, bool)> = Vec::with_capacity(6);
+            for k in 0..4 {
+                let next = (k + 1) % 4;
+                if inside[k] {
+                    polygon.push((points[k], false));
+                }
+                if inside[k] != inside[next] {
+                    let t = (iso - values[k]) / (values[next] - values[k]);
+                    polygon.push((points[k].lerp(points[next], t), true));
+                }
+            }
+
+            for k in 1..polygon.len() - 1 {
+                contour.triangles.extend_from_slice(&[polygon[0].0, polygon[k].0, polygon[k + 1].0]);
+            }
+            for k in 0..polygon.len() {
+                let (a, b) = (polygon[k], polygon[(k + 1) % polygon.len()]);
+                if a.1 && b.1 {
+                    contour.segments.extend_from_slice(&[a.0, b.0]);
+                }
+            }
+        }
+    }
+    contour
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(triangles: &[Vec2]) -> f32 {
+        triangles.chunks_exact(3).map(|t| 0.5 * (t[1] - t[0]).perp_dot(t[2] - t[0])).sum()
+    }
+
+    // A single cell with unit spacing, corners counterclockwise from the origin
+    fn cell(values: [f32; 4]) -> DensityGrid {
+        DensityGrid { nx: 2, ny: 2, spacing: 1.0, values: vec![values[0], values[1], values[3], values[2]] }
+    }
+
+    #[test]
+    fn empty_grid() {
+        let grid = DensityGrid::splat(&[], 1.0, 1.0, 0.1, 0.2, 0.05);
+        assert!(grid.values.iter().all(|v| *v == 0.0));
+        let contour = marching_squares(&grid, 0.5);
+        assert!(contour.triangles.is_empty() && contour.segments.is_empty());
+
+        // Too small for a single cell
+        let grid = DensityGrid { nx: 1, ny: 1, spacing: 1.0, values: vec![1.0] };
+        let contour = marching_squares(&grid, 0.5);
+        assert!(contour.triangles.is_empty() && contour.segments.is_empty());
+    }
+
+    #[test]
+    fn single_blob() {
+        let center = Vec2::new(0.5, 0.5);
+        let grid = DensityGrid::splat(&[center], 1.0, 1.0, 0.01, 0.2, 0.2);
+        let contour = marching_squares(&grid, 0.5);
+
+        // The kernel (1 - q²)³ at the peak value 4/π crosses 0.5 at this radius
+        let radius = 0.2 * (1.0 - (0.5 * std::f32::consts::PI / 4.0).cbrt()).sqrt();
+        let expected = std::f32::consts::PI * radius * radius;
+        assert!((area(&contour.triangles) - expected).abs() < 0.02 * expected);
+        // Counterclockwise everywhere
+        assert!(contour.triangles.chunks_exact(3).all(|t| (t[1] - t[0]).perp_dot(t[2] - t[0]) >= -1e-9));
+        for point in &contour.segments {
+            assert!(((*point - center).length() - radius).abs() < 0.01);
+        }
+        // A closed outline, every end point is shared by exactly two segments
+        for point in &contour.segments {
+            let count = contour.segments.iter().filter(|other| (**other - *point).length() < 1e-5).count();
+            assert_eq!(count, 2);
+        }
+    }
+
+    #[test]
+    fn cell_cases() {
+        // All 16 corner combinations with values 0 and 1, iso 0.5 crosses the edges at their middle
+        for case in 0..16 {
+            let values = [0, 1, 2, 3].map(|k| ((case >> k) & 1) as f32);
+            let inside = values.iter().filter(|v| **v > 0.5).count();
+            let contour = marching_squares(&cell(values), 0.5);
+            let (expected_area, expected_segments) = match (case, inside) {
+                (_, 0) => (0.0, 0), 
+                (_, 4) => (1.0, 0), 
+                (_, 1) => (0.125, 1), 
+                (_, 3) => (0.875, 1), 
+                // Saddles, joined through the middle
+                (0b0101 | 0b1010, 2) => (0.75, 2), 
+                (_, 2) => (0.5, 1), 
+                _ => unreachable!(), 
+            };
+            assert!((area(&contour.triangles) - expected_area).abs() < 1e-6, "case {:04b}", case);
+            assert_eq!(contour.segments.len(), 2 * expected_segments, "case {:04b}", case);
+            for point in contour.segments.iter().chain(&contour.triangles) {
+                assert!(point.cmpge(Vec2::ZERO).all() && point.cmple(Vec2::ONE).all(), "case {:04b}", case);
+            }
+        }
+
+        // Values exactly at the iso level count as outside
+        let contour = marching_squares(&cell([0.5; 4]), 0.5);
+        assert!(contour.triangles.is_empty());
+        // The crossing is interpolated linearly
+        let contour = marching_squares(&cell([1.0, 0.0, 0.0, 0.0]), 0.75);
+        assert!(contour.segments.contains(&Vec2::new(0.25, 0.0)) && contour.segments.contains(&Vec2::new(0.0, 0.25)));
+    }
+}
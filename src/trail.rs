@@ -0,0 +1,130 @@
+use std::collections::VecDeque;
+
+use glam::Vec2;
+
+use crate::solver::Particle;
+
+// Recent particle positions for drawing motion streaks.
+// Keeps the last `length` frames as snapshots, so that particle `i` of every snapshot is the same particle.
+pub struct Trails {
+    pub length: usize, 
+    frames: VecDeque<Vec<Vec2>>, 
+}
+
+impl Trails {
+    pub fn new(length: usize) -> Self {
+        Trails { length, frames: VecDeque::with_capacity(length) }
+    }
+
+    // Adding or removing particles shifts the indices, so the history starts over
+    pub fn record(&mut self, particles: &[Particle]) {
+        if self.frames.back().is_some_and(|frame| frame.len() != particles.len()) {
+            self.frames.clear();
+        }
+        // The oldest snapshot is reused for the new one
+        let mut frame = if self.frames.len() >= self.length.max(1) {
+            self.frames.pop_front().unwrap_or_default()
+        } else {
+            Vec::with_capacity(particles.len())
+        };
+        while self.frames.len() >= self.length.max(1) {
+            self.frames.pop_front();
+        }
+        frame.clear();
+        frame.extend(particles.iter().map(|particle| particle.position));
+        self.frames.push_back(frame);
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    // Calls `f(i, from, to, age)` for each streak segment of particle `i`, oldest first.
+    // `age` runs from 0.0 for the newest segment towards 1.0 for the oldest.
+    pub fn for_each_segment<F: FnMut(usize, Vec2, Vec2, f32)>(&self, mut f: F) {
+        let count = self.frames.len();
+        for k in 1..count {
+            let age = (count - k) as f32 / count as f32;
+            let (from, to) = (&self.frames[k - 1], &self.frames[k]);
+            for (i, (a, b)) in from.iter().zip(to.iter()).enumerate() {
+                f(i, *a, *b, age);
+            }
+        }
+    }
+
+    // Particles in the newest snapshot, None before the first
+    pub fn particle_count(&self) -> Option<usize> {
+        self.frames.back().map(Vec::len)
+    }
+
+    pub fn num_segments(&self) -> usize {
+        self.frames.len().saturating_sub(1) * self.frames.back().map_or(0, |frame| frame.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::{Scene, State};
+
+    fn particles(count: u32) -> Vec<Particle> {
+        State::new(&Scene { num_particles: count, field_width: 0.4, field_height: 0.2, ..Default::default() }).particles
+    }
+
+    // Records `frames` frames with every particle at x = frame index
+    fn record_frames(trails: &mut Trails, particles: &mut [Particle], frames: std::ops::Range<usize>) {
+        for frame in frames {
+            particles.iter_mut().for_each(|particle| particle.position = Vec2::new(frame as f32, 0.0));
+            trails.record(particles);
+        }
+    }
+
+    fn segments(trails: &Trails) -> Vec<(usize, Vec2, Vec2, f32)> {
+        let mut segments = Vec::new();
+        trails.for_each_segment(|i, from, to, age| segments.push((i, from, to, age)));
+        segments
+    }
+
+    #[test]
+    fn history_restarts_when_the_count_changes() {
+        let mut trails = Trails::new(4);
+        let mut three = particles(3);
+        record_frames(&mut trails, &mut three, 0..3);
+        assert_eq!(trails.num_segments(), 2 * 3);
+        // One particle deleted
+        let mut two = particles(2);
+        record_frames(&mut trails, &mut two, 3..4);
+        assert_eq!(trails.particle_count(), Some(2));
+        assert_eq!(trails.num_segments(), 0);
+        record_frames(&mut trails, &mut two, 4..5);
+        assert_eq!(segments(&trails).len(), 2);
+    }
+
+    #[test]
+    fn shorter_length_drops_the_oldest_frames() {
+        let mut trails = Trails::new(5);
+        let mut particles = particles(1);
+        record_frames(&mut trails, &mut particles, 0..5);
+        assert_eq!(trails.num_segments(), 4);
+        trails.length = 2;
+        record_frames(&mut trails, &mut particles, 5..6);
+        let segments = segments(&trails);
+        assert_eq!(segments.len(), 1);
+        assert_eq!((segments[0].1.x, segments[0].2.x), (4.0, 5.0));
+    }
+
+    #[test]
+    fn segments_run_from_oldest_to_newest() {
+        let mut trails = Trails::new(4);
+        let mut particles = particles(2);
+        record_frames(&mut trails, &mut particles, 0..6);
+        let segments = segments(&trails);
+        assert_eq!(segments.len(), 3 * 2);
+        // Frames 2 to 5 are left, ages fall towards the newest segment
+        let starts: Vec<f32> = segments.iter().map(|segment| segment.1.x).collect();
+        assert_eq!(starts, vec![2.0, 2.0, 3.0, 3.0, 4.0, 4.0]);
+        assert!(segments.windows(2).all(|pair| pair[0].3 >= pair[1].3));
+        assert!(segments[0].3 < 1.0 && segments[5].3 > 0.0);
+        assert_eq!(segments.iter().map(|segment| segment.0).collect::<Vec<_>>(), vec![0, 1, 0, 1, 0, 1]);
+    }
+}